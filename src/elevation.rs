@@ -0,0 +1,73 @@
+use rust_geotiff::TIFF;
+
+/// A digital elevation model, wrapping a GeoTIFF together with the information required
+/// to map longitudes and latitudes onto its raster cells.
+pub struct ElevationModel {
+    /// The underlying raster data.
+    pub dem: Box<TIFF>,
+    /// The longitude of the upper left corner of the raster.
+    pub origin_lon: f64,
+    /// The latitude of the upper left corner of the raster.
+    pub origin_lat: f64,
+    /// The width of a single raster cell, in degrees longitude.
+    pub cell_width: f64,
+    /// The height of a single raster cell, in degrees latitude.
+    pub cell_height: f64,
+}
+
+impl ElevationModel {
+    /// Creates a new elevation model from a GeoTIFF and its georeferencing.
+    pub fn new(dem: Box<TIFF>, origin_lon: f64, origin_lat: f64,
+               cell_width: f64, cell_height: f64) -> ElevationModel {
+        ElevationModel {
+            dem: dem,
+            origin_lon: origin_lon,
+            origin_lat: origin_lat,
+            cell_width: cell_width,
+            cell_height: cell_height,
+        }
+    }
+
+    /// The number of rows of the raster.
+    fn rows(&self) -> usize {
+        self.dem.image_data.len()
+    }
+
+    /// The number of columns of the raster.
+    fn cols(&self) -> usize {
+        self.dem.image_data.first().map(|r| r.len()).unwrap_or(0)
+    }
+
+    /// Returns the elevation at the given longitude and latitude, bilinearly interpolated
+    /// between the four surrounding cell centers. Returns `None` if the location lies outside
+    /// of the raster.
+    pub fn elevation_at(&self, lon: f64, lat: f64) -> Option<f64> {
+        // Cell values refer to the cell centers, hence the shift by half a cell.
+        let x = (lon - self.origin_lon) / self.cell_width - 0.5;
+        let y = (self.origin_lat - lat) / self.cell_height - 0.5;
+        let (rows, cols) = (self.rows(), self.cols());
+        if rows == 0 || cols == 0 || x < -0.5 || y < -0.5 ||
+            x > cols as f64 - 0.5 || y > rows as f64 - 0.5 {
+            return None;
+        }
+
+        // Clamp to the raster, so that locations in the outermost half cells use the
+        // border values.
+        let x = x.max(0.0).min((cols - 1) as f64);
+        let y = y.max(0.0).min((rows - 1) as f64);
+        let col0 = x.floor() as usize;
+        let row0 = y.floor() as usize;
+        let col1 = (col0 + 1).min(cols - 1);
+        let row1 = (row0 + 1).min(rows - 1);
+        let dx = x - col0 as f64;
+        let dy = y - row0 as f64;
+
+        let v00 = self.dem.get_value_at(row0, col0) as f64;
+        let v01 = self.dem.get_value_at(row0, col1) as f64;
+        let v10 = self.dem.get_value_at(row1, col0) as f64;
+        let v11 = self.dem.get_value_at(row1, col1) as f64;
+
+        Some(v00 * (1.0 - dx) * (1.0 - dy) + v01 * dx * (1.0 - dy) +
+             v10 * (1.0 - dx) * dy + v11 * dx * dy)
+    }
+}
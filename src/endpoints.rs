@@ -5,9 +5,14 @@ extern crate geojson;
 use iron::prelude::*;
 use iron::typemap::Key;
 use persistent::Read;
-use graph::core::Graph;
+use graph::core::{Graph, Costs, Metric, Weighting, RoutingAlgorithm, ReachabilityAlgorithm, RoutingError};
+use graph::charging::{ChargingCurve, ChargingStop};
+use graph::vehicle::VehicleProfile;
+use graph::isochrone::Polygon;
+use params::Map;
 use std::collections::BTreeMap;
-use rustc_serialize::json::ToJson;
+use std::str::FromStr;
+use rustc_serialize::json::{self, Json, ToJson};
 use geojson::{Feature, FeatureCollection, GeoJson, Geometry};
 
 /// A pool that abstracts over the graph, and makes it available to all requests.
@@ -15,9 +20,60 @@ pub struct GraphPool;
 
 impl Key for GraphPool { type Value = Graph; }
 
+/// Transforms a routing error into a JSON message, which is returned with HTTP status 404.
+fn error_response(err: &RoutingError) -> Response {
+    let mut message = BTreeMap::new();
+    message.insert(
+        String::from("error"),
+        err.to_string().to_json(),
+    );
+
+    Response::with((iron::status::NotFound, Json::Object(message).to_string()))
+}
+
 /// Transforms the result of a route calculation into a GeoJSON, convenient for sending
-/// over the Internet.
-fn route_res_to_geojson(lat_lons: Vec<Vec<f64>>, cost: f32) -> String {
+/// over the Internet. The total cost is given in terms of the metric that was minimized,
+/// but the totals of all metrics are reported as well.
+fn route_res_to_geojson(lat_lons: Vec<Vec<f64>>, costs: Costs, metric: Metric) -> String {
+    let geometry = Geometry::new(
+        geojson::Value::LineString(lat_lons.iter().map(|x|
+            x.iter().map(|&y| y).collect::<Vec<_>>()
+        ).collect::<Vec<_>>())
+    );
+
+    let mut properties = BTreeMap::new();
+    properties.insert(
+        String::from("total_cost"),
+        costs.get(metric).to_json(),
+    );
+    properties.insert(
+        String::from("total_distance"),
+        costs.distance.to_json(),
+    );
+    properties.insert(
+        String::from("total_duration"),
+        costs.duration.to_json(),
+    );
+    properties.insert(
+        String::from("total_energy"),
+        costs.energy.to_json(),
+    );
+
+    let geojson = GeoJson::Feature(Feature {
+        crs: None,
+        bbox: None,
+        geometry: Some(geometry),
+        id: None,
+        properties: Some(properties),
+    });
+
+    geojson.to_string()
+}
+
+/// Transforms the result of an energy-constrained route calculation into a GeoJSON. In
+/// addition to the total cost (i.e., the energy used), this also reports the energy remaining
+/// at the target.
+fn energy_route_res_to_geojson(lat_lons: Vec<Vec<f64>>, cost: f32, remaining: f32) -> String {
     let geometry = Geometry::new(
         geojson::Value::LineString(lat_lons.iter().map(|x|
             x.iter().map(|&y| y).collect::<Vec<_>>()
@@ -29,6 +85,10 @@ fn route_res_to_geojson(lat_lons: Vec<Vec<f64>>, cost: f32) -> String {
         String::from("total_cost"),
         cost.to_json(),
     );
+    properties.insert(
+        String::from("capacity_remaining"),
+        remaining.to_json(),
+    );
 
     let geojson = GeoJson::Feature(Feature {
         crs: None,
@@ -41,9 +101,151 @@ fn route_res_to_geojson(lat_lons: Vec<Vec<f64>>, cost: f32) -> String {
     geojson.to_string()
 }
 
+/// Transforms the result of a charging-aware route calculation into a GeoJSON FeatureCollection,
+/// containing the route (whose total duration includes the time spent charging) and a Point
+/// for every charging stop.
+fn charging_route_res_to_geojson(graph: &Graph, lat_lons: Vec<Vec<f64>>, costs: Costs,
+                                 stops: Vec<ChargingStop>, remaining: f32) -> String {
+    let charging_duration = stops.iter().map(|s| s.duration).sum::<f32>();
+    let mut props = BTreeMap::new();
+    props.insert(
+        String::from("total_distance"),
+        costs.distance.to_json(),
+    );
+    props.insert(
+        String::from("total_duration"),
+        (costs.duration + charging_duration).to_json(),
+    );
+    props.insert(
+        String::from("total_energy"),
+        costs.energy.to_json(),
+    );
+    props.insert(
+        String::from("charging_duration"),
+        charging_duration.to_json(),
+    );
+    props.insert(
+        String::from("capacity_remaining"),
+        remaining.to_json(),
+    );
+
+    let mut features = vec![Feature {
+        crs: None,
+        bbox: None,
+        geometry: Some(Geometry::new(
+            geojson::Value::LineString(lat_lons)
+        )),
+        id: None,
+        properties: Some(props)
+    }];
+    for stop in stops {
+        let station = &graph.charging_stations[stop.station];
+        let mut props = BTreeMap::new();
+        props.insert(
+            String::from("station_id"),
+            station.id.to_json(),
+        );
+        props.insert(
+            String::from("sockets"),
+            station.sockets.to_json(),
+        );
+        props.insert(
+            String::from("power"),
+            station.power.to_json(),
+        );
+        props.insert(
+            String::from("capacity"),
+            station.capacity.to_json(),
+        );
+        props.insert(
+            String::from("arrival_charge"),
+            stop.arrival_charge.to_json(),
+        );
+        props.insert(
+            String::from("departure_charge"),
+            stop.departure_charge.to_json(),
+        );
+        props.insert(
+            String::from("charging_duration"),
+            stop.duration.to_json(),
+        );
+
+        features.push(Feature {
+            crs: None,
+            bbox: None,
+            geometry: Some(Geometry::new(
+                geojson::Value::Point(vec![station.lon, station.lat])
+            )),
+            id: None,
+            properties: Some(props)
+        });
+    }
+
+    let geojson = GeoJson::FeatureCollection(FeatureCollection {
+        crs: None,
+        bbox: None,
+        features: features,
+    });
+
+    geojson.to_string()
+}
+
+/// Transforms the Pareto front of routes into a GeoJSON FeatureCollection, containing one
+/// Feature with the totals of all metrics per route.
+fn pareto_res_to_geojson(routes: Vec<(Vec<Vec<f64>>, Costs)>) -> String {
+    let mut features = Vec::new();
+    for (lat_lons, costs) in routes {
+        let mut props = BTreeMap::new();
+        props.insert(
+            String::from("total_distance"),
+            costs.distance.to_json(),
+        );
+        props.insert(
+            String::from("total_duration"),
+            costs.duration.to_json(),
+        );
+        props.insert(
+            String::from("total_energy"),
+            costs.energy.to_json(),
+        );
+
+        features.push(Feature {
+            crs: None,
+            bbox: None,
+            geometry: Some(Geometry::new(
+                geojson::Value::LineString(lat_lons)
+            )),
+            id: None,
+            properties: Some(props)
+        });
+    }
+
+    let geojson = GeoJson::FeatureCollection(FeatureCollection {
+        crs: None,
+        bbox: None,
+        features: features,
+    });
+
+    geojson.to_string()
+}
+
+/// Transforms a GeoJSON object into a string, adding the name of the reachability algorithm
+/// that was used as a member.
+fn with_algorithm(geojson: GeoJson, algorithm: ReachabilityAlgorithm) -> String {
+    let mut json = geojson.to_json();
+    if let Json::Object(ref mut members) = json {
+        members.insert(
+            String::from("algorithm"),
+            algorithm.name().to_json(),
+        );
+    }
+
+    json.to_string()
+}
+
 /// Transforms the result of a reachability calculation to a GeoJSON string, ready
 /// to be processed in the frontend.
-fn reachability_res_to_geojson(lat_lon_caps: Vec<Vec<f64>>) -> String {
+fn reachability_res_to_geojson(lat_lon_caps: Vec<Vec<f64>>, algorithm: ReachabilityAlgorithm) -> String {
     let mut features = Vec::new();
     for lat_lon in lat_lon_caps {
         let mut props = BTreeMap::new();
@@ -70,29 +272,253 @@ fn reachability_res_to_geojson(lat_lon_caps: Vec<Vec<f64>>) -> String {
         features: features,
     });
 
+    with_algorithm(geojson, algorithm)
+}
+
+/// Transforms the nodes closest to a location (given as internal id and distance in kilometers)
+/// into a GeoJSON FeatureCollection, containing a Point for every node, ordered by distance.
+fn nearest_res_to_geojson(graph: &Graph, nodes: Vec<(usize, f64)>) -> String {
+    let mut features = Vec::new();
+    for (id, distance) in nodes {
+        let mut props = BTreeMap::new();
+        props.insert(
+            String::from("id"),
+            graph.nodes[id].id.to_json(),
+        );
+        props.insert(
+            String::from("distance"),
+            (distance * 1000.0).to_json(),
+        );
+
+        features.push(Feature {
+            crs: None,
+            bbox: None,
+            geometry: Some(Geometry::new(geojson::Value::Point(graph.get_loc_from_id(id)))),
+            id: None,
+            properties: Some(props)
+        });
+    }
+
+    let geojson = GeoJson::FeatureCollection(FeatureCollection {
+        crs: None,
+        bbox: None,
+        features: features,
+    });
+
     geojson.to_string()
 }
 
+/// Transforms the isochrones of a reachability calculation into a GeoJSON FeatureCollection,
+/// containing a Polygon for every area reachable within a threshold.
+fn isochrones_res_to_geojson(isochrones: Vec<(f32, Vec<Polygon>)>, capacity: f32,
+                             algorithm: ReachabilityAlgorithm) -> String {
+    let mut features = Vec::new();
+    for (threshold, polygons) in isochrones {
+        for polygon in polygons {
+            let mut props = BTreeMap::new();
+            props.insert(
+                String::from("threshold"),
+                threshold.to_json(),
+            );
+            props.insert(
+                String::from("capacity"),
+                (capacity * threshold / 100.0).to_json(),
+            );
+
+            features.push(Feature {
+                crs: None,
+                bbox: None,
+                geometry: Some(Geometry::new(
+                    geojson::Value::Polygon(polygon)
+                )),
+                id: None,
+                properties: Some(props)
+            });
+        }
+    }
+
+    let geojson = GeoJson::FeatureCollection(FeatureCollection {
+        crs: None,
+        bbox: None,
+        features: features,
+    });
+
+    with_algorithm(geojson, algorithm)
+}
+
+/// Reads the optional `algorithm` parameter of a routing request. Defaults to Djikstra if the
+/// weighting has `negative_weights`, which no other algorithm supports, to the turn-aware
+/// Djikstra if the graph has turn restrictions or turn costs, which the other algorithms ignore, to
+/// contraction hierarchies if the graph has a hierarchy (for transport modes without one, the
+/// routing falls back to bidirectional Djikstra), and to Djikstra otherwise. Returns `None` if the
+/// algorithm is unknown.
+fn algorithm_param(map: &Map, graph: &Graph, negative_weights: bool) -> Option<RoutingAlgorithm> {
+    use params::Value;
+
+    match map.find(&["algorithm"]) {
+        Some(&Value::String(ref name)) => RoutingAlgorithm::from_name(name),
+        _ if negative_weights => Some(RoutingAlgorithm::Djikstra),
+        _ if !graph.turn_restrictions.is_empty() || !graph.turn_costs.is_empty() =>
+            Some(RoutingAlgorithm::TurnAwareDjikstra),
+        _ if !graph.hierarchies.is_empty() => Some(RoutingAlgorithm::ContractionHierarchies),
+        _ => Some(RoutingAlgorithm::Djikstra)
+    }
+}
+
+/// Reads the optional `mode`, `metric` and `vehicle` parameters of a request, i.e., the name of
+/// a transport mode of the graph, the metric to minimize, and a vehicle profile given as JSON.
+/// Defaults to the first transport mode, the given metric, and the energy computed by the
+/// transport mode script. Returns `None` if the transport mode or metric is unknown, or the
+/// vehicle profile is invalid.
+fn weighting_param(map: &Map, graph: &Graph, default_metric: Metric) -> Option<Weighting> {
+    use params::Value;
+
+    let mode = match map.find(&["mode"]) {
+        Some(&Value::String(ref name)) => graph.mode_index(name),
+        _ => Some(0)
+    };
+    let metric = match map.find(&["metric"]) {
+        Some(&Value::String(ref name)) => Metric::from_name(name),
+        _ => Some(default_metric)
+    };
+    let vehicle = match map.find(&["vehicle"]) {
+        Some(&Value::String(ref profile)) => json::decode::<VehicleProfile>(profile).ok().map(Some),
+        _ => Some(None)
+    };
+
+    match (mode, metric, vehicle) {
+        (Some(mode), Some(metric), Some(vehicle)) =>
+            Some(Weighting { mode: mode, metric: metric, vehicle: vehicle }),
+        _ => None
+    }
+}
+
+/// Reads the optional `max-snap-distance` parameter (in meters) of a request, and returns it
+/// in kilometers. Returns `None` if the distance is not a number.
+fn max_snap_distance_param(map: &Map) -> Option<Option<f64>> {
+    use params::Value;
+
+    match map.find(&["max-snap-distance"]) {
+        Some(&Value::String(ref distance)) => distance.parse::<f64>().ok().map(|d| Some(d / 1000.0)),
+        _ => Some(None)
+    }
+}
+
+/// Reads the optional parameter with the given name of a request, and parses it. Returns an
+/// `InvalidParameter` error if it cannot be parsed.
+fn parse_param<T: FromStr>(map: &Map, name: &str) -> Result<Option<T>, RoutingError> {
+    use params::Value;
+
+    match map.find(&[name]) {
+        Some(&Value::String(ref value)) => match value.trim().parse::<T>() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(RoutingError::InvalidParameter(String::from(name), value.clone()))
+        },
+        _ => Ok(None)
+    }
+}
+
+/// Reads the `capacity` parameter of a request, i.e., the battery capacity of the vehicle (or
+/// the capacity in terms of the metric). Returns an `InvalidParameter` error if it is not a
+/// positive number.
+fn capacity_param(map: &Map) -> Result<Option<f32>, RoutingError> {
+    match parse_param::<f32>(map, "capacity") {
+        Ok(Some(capacity)) if !(capacity > 0.0 && capacity.is_finite()) =>
+            Err(RoutingError::InvalidParameter(String::from("capacity"), capacity.to_string())),
+        res => res
+    }
+}
+
+/// Reads the optional `max-detour` parameter of a Pareto search, i.e., the factor by which the
+/// routes may take longer than the fastest one. Defaults to 1.5. Returns an `InvalidParameter`
+/// error if it is less than 1.
+fn max_detour_param(map: &Map) -> Result<f32, RoutingError> {
+    match parse_param::<f32>(map, "max-detour") {
+        Ok(Some(max_detour)) if !(max_detour >= 1.0 && max_detour.is_finite()) =>
+            Err(RoutingError::InvalidParameter(String::from("max-detour"), max_detour.to_string())),
+        Ok(max_detour) => Ok(max_detour.unwrap_or(1.5)),
+        Err(err) => Err(err)
+    }
+}
+
+/// Reads the optional `initial-soc` parameter of a charging-aware request, i.e., the initial
+/// state of charge in percent of the capacity. Defaults to 100. Returns an `InvalidParameter`
+/// error if it is not between 0 and 100.
+fn initial_soc_param(map: &Map) -> Result<f32, RoutingError> {
+    match parse_param::<f32>(map, "initial-soc") {
+        Ok(Some(soc)) if !(soc >= 0.0 && soc <= 100.0) =>
+            Err(RoutingError::InvalidParameter(String::from("initial-soc"), soc.to_string())),
+        Ok(soc) => Ok(soc.unwrap_or(100.0)),
+        Err(err) => Err(err)
+    }
+}
+
+/// Reads the optional `thresholds` parameter of a reachability request, i.e., comma-separated
+/// shares of the capacity in percent. Returns an `InvalidParameter` error if any of them is not a
+/// non-negative number.
+fn thresholds_param(map: &Map) -> Result<Option<Vec<f32>>, RoutingError> {
+    use params::Value;
+
+    match map.find(&["thresholds"]) {
+        Some(&Value::String(ref thresholds)) => {
+            let parsed = thresholds.split(',')
+                .map(|t| match t.trim().parse::<f32>() {
+                    Ok(t) if t >= 0.0 && t.is_finite() => Some(t),
+                    _ => None
+                })
+                .collect::<Option<Vec<_>>>();
+            match parsed {
+                Some(parsed) => Ok(Some(parsed)),
+                None => Err(RoutingError::InvalidParameter(String::from("thresholds"), thresholds.clone()))
+            }
+        },
+        _ => Ok(None)
+    }
+}
+
+/// Reads the optional `alpha` parameter (in meters) of a reachability request, and returns it in
+/// kilometers. Defaults to 500 meters. Returns an `InvalidParameter` error if it is not positive.
+fn alpha_param(map: &Map) -> Result<f64, RoutingError> {
+    match parse_param::<f64>(map, "alpha") {
+        Ok(Some(alpha)) if !(alpha > 0.0 && alpha.is_finite()) =>
+            Err(RoutingError::InvalidParameter(String::from("alpha"), alpha.to_string())),
+        Ok(alpha) => Ok(alpha.map(|alpha| alpha / 1000.0).unwrap_or(0.5)),
+        Err(err) => Err(err)
+    }
+}
+
 /// Computes a route, given a start and end latitude and longitude.
 pub fn route_lat_lon(req: &mut Request) -> IronResult<Response> {
     let graph = req.get::<Read<GraphPool>>().unwrap();
     use params::{Params, Value};
     let map = req.get_ref::<Params>().unwrap();
 
+    let weighting = weighting_param(map, &graph, Metric::Duration);
+    let negative_weights = weighting.map_or(false, |w| graph.has_negative_weights(w));
     match (map.find(&["source-lon"]), map.find(&["source-lat"]),
-           map.find(&["target-lon"]), map.find(&["target-lat"])) {
+           map.find(&["target-lon"]), map.find(&["target-lat"]),
+           algorithm_param(map, &graph, negative_weights), weighting, max_snap_distance_param(map)) {
         (Some(&Value::String(ref source_lon)), Some(&Value::String(ref source_lat)),
-            Some(&Value::String(ref target_lon)), Some(&Value::String(ref target_lat))) => {
+            Some(&Value::String(ref target_lon)), Some(&Value::String(ref target_lat)),
+            Some(algorithm), Some(weighting), Some(max_snap_distance)) => {
             let bellman_start = time::now();
-            println!("Starting Bellman-Ford ...");
-            let source_id = graph.get_id_from_lon_lat(source_lon.parse::<f64>().unwrap(),
-                                                      source_lat.parse::<f64>().unwrap());
-            let target_id = graph.get_id_from_lon_lat(target_lon.parse::<f64>().unwrap(),
-                                                      target_lat.parse::<f64>().unwrap());
-            let res = graph.route(source_id, target_id);
+            println!("Starting {:?} for {} ({:?}) ...", algorithm, graph.modes[weighting.mode], weighting.metric);
+            let source = graph.snap_to_edge(source_lon.parse::<f64>().unwrap(),
+                                            source_lat.parse::<f64>().unwrap(), max_snap_distance,
+                                            weighting);
+            let target = graph.snap_to_edge(target_lon.parse::<f64>().unwrap(),
+                                            target_lat.parse::<f64>().unwrap(), max_snap_distance,
+                                            weighting);
+            let res = source.and_then(|s| target.and_then(|t| {
+                graph.route(&s, &t, algorithm, weighting, negative_weights)
+            }));
             println!(" ˪— duration: {}s\n", (time::now() - bellman_start).num_seconds());
 
-            Ok(Response::with((iron::status::Ok, route_res_to_geojson(res.0, res.1))))
+            match res {
+                Ok((trace, costs)) =>
+                    Ok(Response::with((iron::status::Ok, route_res_to_geojson(trace, costs, weighting.metric)))),
+                Err(err) => Ok(error_response(&err))
+            }
         }
         _ => Ok(Response::with(iron::status::NotFound))
     }
@@ -100,41 +526,218 @@ pub fn route_lat_lon(req: &mut Request) -> IronResult<Response> {
 
 /// Computes a route, given a start and end OSM ID.
 pub fn route_ids(req: &mut Request) -> IronResult<Response> {
+    let graph = req.get::<Read<GraphPool>>().unwrap();
+    use params::Params;
+    let map = req.get_ref::<Params>().unwrap();
+
+    let (source_id, target_id) = match (parse_param::<i64>(map, "source-id"), parse_param::<i64>(map, "target-id")) {
+        (Ok(source_id), Ok(target_id)) => (source_id, target_id),
+        (Err(err), _) | (_, Err(err)) => return Ok(error_response(&err))
+    };
+    let weighting = weighting_param(map, &graph, Metric::Duration);
+    let negative_weights = weighting.map_or(false, |w| graph.has_negative_weights(w));
+    match (source_id, target_id, algorithm_param(map, &graph, negative_weights), weighting) {
+        (Some(source_id), Some(target_id), Some(algorithm), Some(weighting)) => {
+            let bellman_start = time::now();
+            println!("Starting {:?} for {} ({:?}) ...", algorithm, graph.modes[weighting.mode], weighting.metric);
+            let source = graph.get_id_from_osm(source_id);
+            let target = graph.get_id_from_osm(target_id);
+            let res = source.and_then(|s| target.and_then(|t| {
+                graph.route(&graph.virtual_node(s), &graph.virtual_node(t), algorithm, weighting, negative_weights)
+            }));
+            println!(" ˪— duration: {}s\n", (time::now() - bellman_start).num_seconds());
+
+            match res {
+                Ok((trace, costs)) =>
+                    Ok(Response::with((iron::status::Ok, route_res_to_geojson(trace, costs, weighting.metric)))),
+                Err(err) => Ok(error_response(&err))
+            }
+        }
+        _ => Ok(Response::with(iron::status::NotFound))
+    }
+}
+
+/// Computes the Pareto front of routes in terms of duration and energy, given a start and end
+/// latitude and longitude.
+pub fn pareto_route(req: &mut Request) -> IronResult<Response> {
     let graph = req.get::<Read<GraphPool>>().unwrap();
     use params::{Params, Value};
     let map = req.get_ref::<Params>().unwrap();
 
-    match (map.find(&["source-id"]), map.find(&["target-id"])) {
-        (Some(&Value::String(ref source_id)), Some(&Value::String(ref target_id))) => {
+    let max_detour = match max_detour_param(map) {
+        Ok(max_detour) => max_detour,
+        Err(err) => return Ok(error_response(&err))
+    };
+    match (map.find(&["source-lon"]), map.find(&["source-lat"]),
+           map.find(&["target-lon"]), map.find(&["target-lat"]),
+           weighting_param(map, &graph, Metric::Duration), max_snap_distance_param(map)) {
+        (Some(&Value::String(ref source_lon)), Some(&Value::String(ref source_lat)),
+            Some(&Value::String(ref target_lon)), Some(&Value::String(ref target_lat)),
+            Some(weighting), Some(max_snap_distance)) => {
             let bellman_start = time::now();
-            println!("Starting Bellman-Ford ...");
-            let res = graph.route(source_id.parse::<i64>().unwrap(),
-                                  target_id.parse::<i64>().unwrap());
+            println!("Starting Pareto search for {} ...", graph.modes[weighting.mode]);
+            let source = graph.snap_to_edge(source_lon.parse::<f64>().unwrap(),
+                                            source_lat.parse::<f64>().unwrap(), max_snap_distance,
+                                            weighting);
+            let target = graph.snap_to_edge(target_lon.parse::<f64>().unwrap(),
+                                            target_lat.parse::<f64>().unwrap(), max_snap_distance,
+                                            weighting);
+            let res = source.and_then(|s| target.and_then(|t| {
+                graph.pareto_route(&s, &t, weighting, max_detour)
+            }));
             println!(" ˪— duration: {}s\n", (time::now() - bellman_start).num_seconds());
 
-            Ok(Response::with((iron::status::Ok, route_res_to_geojson(res.0, res.1))))
+            match res {
+                Ok(routes) => Ok(Response::with((iron::status::Ok, pareto_res_to_geojson(routes)))),
+                Err(err) => Ok(error_response(&err))
+            }
         }
         _ => Ok(Response::with(iron::status::NotFound))
     }
 }
 
-/// Returns all reachable nodes in a vicinity. This can be a lot, so take care!
+/// Computes an energy-constrained route, given a start and end latitude and longitude, as well
+/// as the battery capacity of the vehicle.
+pub fn energy_route(req: &mut Request) -> IronResult<Response> {
+    let graph = req.get::<Read<GraphPool>>().unwrap();
+    use params::{Params, Value};
+    let map = req.get_ref::<Params>().unwrap();
+
+    let capacity = match capacity_param(map) {
+        Ok(capacity) => capacity,
+        Err(err) => return Ok(error_response(&err))
+    };
+    match (map.find(&["source-lon"]), map.find(&["source-lat"]),
+           map.find(&["target-lon"]), map.find(&["target-lat"]), capacity,
+           weighting_param(map, &graph, Metric::Energy), max_snap_distance_param(map)) {
+        (Some(&Value::String(ref source_lon)), Some(&Value::String(ref source_lat)),
+            Some(&Value::String(ref target_lon)), Some(&Value::String(ref target_lat)),
+            Some(capacity), Some(weighting), Some(max_snap_distance)) => {
+            let bellman_start = time::now();
+            println!("Starting Energy Bellman-Ford for {} ({:?}) ...", graph.modes[weighting.mode], weighting.metric);
+            let source = graph.snap(source_lon.parse::<f64>().unwrap(),
+                                    source_lat.parse::<f64>().unwrap(), max_snap_distance, weighting.mode);
+            let target = graph.snap(target_lon.parse::<f64>().unwrap(),
+                                    target_lat.parse::<f64>().unwrap(), max_snap_distance, weighting.mode);
+            let res = source.and_then(|s| target.and_then(|t| graph.energy_route(s, t, capacity, weighting)));
+            println!(" ˪— duration: {}s\n", (time::now() - bellman_start).num_seconds());
+
+            match res {
+                Ok((trace, cost, remaining)) =>
+                    Ok(Response::with((iron::status::Ok, energy_route_res_to_geojson(trace, cost, remaining)))),
+                Err(err) => Ok(error_response(&err))
+            }
+        }
+        _ => Ok(Response::with(iron::status::NotFound))
+    }
+}
+
+/// Computes the fastest route for an electric vehicle including charging stops, given a start
+/// and end latitude and longitude, the battery capacity, and the charging curve of the vehicle.
+pub fn charging_route(req: &mut Request) -> IronResult<Response> {
+    let graph = req.get::<Read<GraphPool>>().unwrap();
+    use params::{Params, Value};
+    let map = req.get_ref::<Params>().unwrap();
+
+    let curve = match map.find(&["charging-curve"]) {
+        Some(&Value::String(ref curve)) => ChargingCurve::parse(curve),
+        _ => None
+    };
+    let (capacity, initial_soc) = match (capacity_param(map), initial_soc_param(map)) {
+        (Ok(capacity), Ok(initial_soc)) => (capacity, initial_soc),
+        (Err(err), _) | (_, Err(err)) => return Ok(error_response(&err))
+    };
+    match (map.find(&["source-lon"]), map.find(&["source-lat"]),
+           map.find(&["target-lon"]), map.find(&["target-lat"]), capacity, curve,
+           weighting_param(map, &graph, Metric::Duration), max_snap_distance_param(map)) {
+        (Some(&Value::String(ref source_lon)), Some(&Value::String(ref source_lat)),
+            Some(&Value::String(ref target_lon)), Some(&Value::String(ref target_lat)),
+            Some(capacity), Some(curve), Some(weighting), Some(max_snap_distance)) => {
+            let bellman_start = time::now();
+            println!("Starting charging-aware routing for {} ...", graph.modes[weighting.mode]);
+            let source = graph.snap(source_lon.parse::<f64>().unwrap(),
+                                    source_lat.parse::<f64>().unwrap(), max_snap_distance, weighting.mode);
+            let target = graph.snap(target_lon.parse::<f64>().unwrap(),
+                                    target_lat.parse::<f64>().unwrap(), max_snap_distance, weighting.mode);
+            let res = source.and_then(|s| target.and_then(|t| {
+                graph.charging_route(s, t, weighting, capacity, capacity * initial_soc / 100.0, &curve)
+            }));
+            println!(" ˪— duration: {}s\n", (time::now() - bellman_start).num_seconds());
+
+            match res {
+                Ok((trace, costs, stops, remaining)) => Ok(Response::with((iron::status::Ok,
+                    charging_route_res_to_geojson(&graph, trace, costs, stops, remaining)))),
+                Err(err) => Ok(error_response(&err))
+            }
+        }
+        _ => Ok(Response::with(iron::status::NotFound))
+    }
+}
+
+/// Returns all reachable nodes in a vicinity. This can be a lot, so take care! If `thresholds`
+/// are given, the isochrones of the reachable areas are returned instead.
 pub fn reachability(req: &mut Request) -> IronResult<Response> {
     let graph = req.get::<Read<GraphPool>>().unwrap();
     use params::{Params, Value};
     let map = req.get_ref::<Params>().unwrap();
 
-    match (map.find(&["source-lon"]), map.find(&["source-lat"]), map.find(&["capacity"])) {
+    let (capacity, thresholds, alpha) = match (capacity_param(map), thresholds_param(map), alpha_param(map)) {
+        (Ok(capacity), Ok(thresholds), Ok(alpha)) => (capacity, thresholds, alpha),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => return Ok(error_response(&err))
+    };
+    match (map.find(&["source-lon"]), map.find(&["source-lat"]), capacity,
+           weighting_param(map, &graph, Metric::Energy), max_snap_distance_param(map)) {
         (Some(&Value::String(ref source_lon)), Some(&Value::String(ref source_lat)),
-            Some(&Value::String(ref capacity))) => {
+            Some(capacity), Some(weighting), Some(max_snap_distance)) => {
             let bellman_start = time::now();
-            println!("Starting Reachability Bellman-Ford ...");
-            let source_id = graph.get_id_from_lon_lat(source_lon.parse::<f64>().unwrap(),
-                                                      source_lat.parse::<f64>().unwrap());
-            let res = graph.reachability(source_id, capacity.parse::<f32>().unwrap());
+            println!("Starting Reachability for {} ({:?}) ...",
+                     graph.modes[weighting.mode], weighting.metric);
+            let source = graph.snap(source_lon.parse::<f64>().unwrap(),
+                                    source_lat.parse::<f64>().unwrap(), max_snap_distance,
+                                    weighting.mode);
+            let res = match thresholds {
+                Some(thresholds) =>
+                    source.and_then(|s| graph.isochrones(s, capacity, weighting, &thresholds, alpha))
+                        .map(|(isochrones, algorithm)| isochrones_res_to_geojson(isochrones, capacity, algorithm)),
+                None => source.and_then(|s| graph.reachability(s, capacity, weighting))
+                    .map(|(res, algorithm)| reachability_res_to_geojson(res, algorithm))
+            };
             println!(" ˪— duration: {}s\n", (time::now() - bellman_start).num_seconds());
 
-            Ok(Response::with((iron::status::Ok, reachability_res_to_geojson(res))))
+            match res {
+                Ok(res) => Ok(Response::with((iron::status::Ok, res))),
+                Err(err) => Ok(error_response(&err))
+            }
+        }
+        _ => Ok(Response::with(iron::status::NotFound))
+    }
+}
+
+/// Returns the nodes closest to a latitude and longitude, i.e., the `k` closest nodes or, if a
+/// `radius` is given, all nodes within it.
+pub fn nearest(req: &mut Request) -> IronResult<Response> {
+    let graph = req.get::<Read<GraphPool>>().unwrap();
+    use params::{Params, Value};
+    let map = req.get_ref::<Params>().unwrap();
+
+    let k = match map.find(&["k"]) {
+        Some(&Value::String(ref k)) => k.parse::<usize>().ok(),
+        _ => Some(1)
+    };
+    let radius = match map.find(&["radius"]) {
+        Some(&Value::String(ref radius)) => radius.parse::<f64>().ok().map(|r| Some(r / 1000.0)),
+        _ => Some(None)
+    };
+    match (map.find(&["lon"]), map.find(&["lat"]), k, radius) {
+        (Some(&Value::String(ref lon)), Some(&Value::String(ref lat)), Some(k), Some(radius)) => {
+            let lon = lon.parse::<f64>().unwrap();
+            let lat = lat.parse::<f64>().unwrap();
+            let nodes = match radius {
+                Some(radius) => graph.nodes_within(lon, lat, radius),
+                None => graph.nearest_nodes(lon, lat, k)
+            };
+
+            Ok(Response::with((iron::status::Ok, nearest_res_to_geojson(&graph, nodes))))
         }
         _ => Ok(Response::with(iron::status::NotFound))
     }
@@ -2,112 +2,671 @@ use std::fs::File;
 use std::io::Read;
 use byteorder::{LittleEndian, ReadBytesExt};
 use spade::rtree::RTree;
-use osmpbfreader::{OsmPbfReader, OsmObj};
-use std::collections::{HashMap, HashSet};
+use osmpbfreader::{OsmPbfReader, OsmObj, OsmId, Relation, Tags};
+use std::collections::HashMap;
 use gluon;
 use gluon::vm::api::{OpaqueValue, Hole, FunctionRef, IO};
 use gluon::vm::thread::Thread;
 
-use graph::core::{Graph, Node, Edge};
+use graph::core::{Graph, Node, Edge, Costs, TurnCost, build_adjacency};
 use graph::serializer::SerializableGraph;
+use graph::charging::ChargingStation;
+use graph::components::keep_largest_components;
+use graph::restriction::{OsmRestriction, resolve_restrictions};
 
-use rust_geotiff::TIFF;
+use elevation::ElevationModel;
 
-const MODULE_NAME: &'static &str = &"transport";
-const FN_EDGE_VALID: &'static &str = &"transport.edge_valid";
-const FN_EDGE_WEIGHT: &'static &str = &"transport.edge_weight";
+const PRELUDE_MODULE_NAME: &'static &str = &"transport_prelude";
+const FN_EDGE_VALID: &'static &str = &"edge_valid";
+const FN_EDGE_COSTS: &'static &str = &"edge_costs";
+const FN_EDGE_DIRECTION_VALID: &'static &str = &"edge_direction_valid";
+const FN_TURN_COST: &'static &str = &"turn_cost";
+const VAL_ONEWAY_KEY: &'static &str = &"oneway_key";
+const VAL_RESTRICTION_CLASSES: &'static &str = &"restriction_classes";
+
+/// The minimal elevation difference (in meters) to the neighbouring nodes of a way for a node to
+/// be considered a peak or a valley, which is kept as a node of the graph.
+const ELEVATION_TOLERANCE: f64 = 1.0;
+
+// Ways are passed to the Gluon scripts as an array of all their (key, value) tags.
+type GluonEdge = Vec<(String, String)>;
+// Nodes are passed as (longitude, latitude, elevation).
+type GluonNode = (f64, f64, f64);
+
+/// The costs of an edge, as returned by the Gluon scripts in a record
+/// `{ distance, duration, energy }`.
+#[derive(Getable, VmType)]
+struct GluonCosts {
+    distance: f64,
+    duration: f64,
+    energy: f64,
+}
+
+/// The functions and values exposed by the Gluon script of a single transport mode.
+struct TransportMode<'vm> {
+    edge_valid: FunctionRef<'vm, fn (GluonEdge) -> bool>,
+    edge_costs: FunctionRef<'vm, fn (GluonEdge, f64, GluonNode, GluonNode) -> GluonCosts>,
+    edge_direction_valid: FunctionRef<'vm, fn (GluonEdge, bool, bool) -> bool>,
+    oneway_key: String,
+    /// The vehicle classes of turn restrictions that apply to the transport mode, from the most
+    /// specific to the most general one (e.g., `motorcar`, `motor_vehicle`, `vehicle`).
+    restriction_classes: Vec<String>,
+    /// The optional function computing the costs of turning from one way onto another.
+    turn_cost: Option<FunctionRef<'vm, fn (GluonEdge, GluonEdge, f64) -> GluonCosts>>,
+}
+
+impl<'vm> TransportMode<'vm> {
+    /// Loads the script of a transport mode as a module with the given name, and exposes
+    /// its functions. The `turn_cost` function is optional.
+    fn load(gluon_vm: &'vm Thread, name: &str, script: &str) -> TransportMode<'vm> {
+        gluon::Compiler::new()
+            .load_script(gluon_vm, name, script)
+            .unwrap();
+        let global = |field: &str| format!("{}.{}", name, field);
+
+        TransportMode {
+            edge_valid: gluon_vm.get_global(&global(FN_EDGE_VALID)).unwrap(),
+            edge_costs: gluon_vm.get_global(&global(FN_EDGE_COSTS)).unwrap(),
+            edge_direction_valid: gluon_vm.get_global(&global(FN_EDGE_DIRECTION_VALID)).unwrap(),
+            oneway_key: gluon_vm.get_global(&global(VAL_ONEWAY_KEY)).unwrap(),
+            restriction_classes: gluon_vm.get_global(&global(VAL_RESTRICTION_CLASSES)).unwrap(),
+            turn_cost: gluon_vm.get_global(&global(FN_TURN_COST)).ok(),
+        }
+    }
+}
+
+/// A way that can be used by at least one transport mode.
+struct UsableWay {
+    /// The OSM id of the way.
+    id: i64,
+    /// The tags of the way.
+    tags: GluonEdge,
+    /// The OSM ids of the nodes of the way.
+    nodes: Vec<i64>,
+    /// Whether each transport mode can traverse the way in the order of its nodes.
+    forward: Vec<bool>,
+    /// Whether each transport mode can traverse the way against the order of its nodes.
+    backward: Vec<bool>,
+    /// The highway tag of the way.
+    highway_tag: String,
+}
 
 pub struct GraphBuilder {}
 
 impl GraphBuilder {
-    pub fn build_from_pbf(pbf: &mut OsmPbfReader<File>, gluon_trans_scr: &mut File, dem: &TIFF) -> SerializableGraph {
-        // Set up everything that is required for Gluon. The Gluon scripts are used
-        // to specify which transport modes are extracted from the OSM file.
-        type GluonEdge = (String);
-        type GluonNode = (f64, f64);
-
+    /// Builds a graph from an OSM pbf file, containing the edge costs for each of the given
+    /// transport mode scripts (given as name and file). An edge is created whenever at least one
+    /// transport mode can use it; the other transport modes get no costs for it.
+    pub fn build_from_pbf(pbf: &mut OsmPbfReader<File>, gluon_prelude_scr: &mut File,
+                          gluon_trans_scrs: &mut Vec<(String, File)>,
+                          dem: &ElevationModel) -> SerializableGraph {
         // Set up the Gluon VM, which compiles the scripts and makes their functions available.
+        // The Gluon scripts are used to specify which transport modes are extracted from the
+        // OSM file.
         let gluon_vm = gluon::new_vm();
-        let mut script = String::new();
-        gluon_trans_scr.read_to_string(&mut script).unwrap();
+        let mut prelude = String::new();
+        gluon_prelude_scr.read_to_string(&mut prelude).unwrap();
 
-        // Load the script and expose the required functions.
+        // Load the prelude first, so that the scripts can import its helpers using
+        // `import! transport_prelude`. Then load every script as its own module.
         gluon::Compiler::new()
-            .load_script(&gluon_vm, MODULE_NAME, &script[..])
-            .unwrap();
-        let mut edge_valid: FunctionRef<fn (GluonEdge) -> bool> = gluon_vm
-            .get_global(FN_EDGE_VALID)
-            .unwrap();
-        let mut edge_weight: FunctionRef<fn (GluonEdge, f64, GluonNode, GluonNode) -> f64> = gluon_vm
-            .get_global(FN_EDGE_WEIGHT)
+            .load_script(&gluon_vm, PRELUDE_MODULE_NAME, &prelude[..])
             .unwrap();
+        let mut modes = Vec::new();
+        let mut transport_modes = Vec::new();
+        for &mut (ref name, ref mut file) in gluon_trans_scrs.iter_mut() {
+            let mut script = String::new();
+            file.read_to_string(&mut script).unwrap();
+            transport_modes.push(TransportMode::load(&gluon_vm, name, &script[..]));
+            modes.push(name.clone());
+        }
 
         // Set up graph building components.
-        let mut important_nodes: HashSet<i64> = HashSet::new();
+        let mut node_uses: HashMap<i64, usize> = HashMap::new();
+        let mut ways: Vec<UsableWay> = Vec::new();
+        let mut way_nodes: HashMap<i64, Node> = HashMap::new();
         let mut node_map: HashMap<i64, i64> = HashMap::new();
         let mut nodes: Vec<Node> = Vec::new();
         let mut edges: Vec<Edge> = Vec::new();
+        let mut charging_stations: Vec<ChargingStation> = Vec::new();
+        let mut osm_restrictions: Vec<OsmRestriction> = Vec::new();
+        let restriction_classes = transport_modes.iter()
+            .map(|m| m.restriction_classes.clone())
+            .collect::<Vec<_>>();
 
-        // First pass to get all important edges. In this first pass, they point from (OSM id
-        // -> OSM id). Later, this is reduced to (Node id -> Node id).
+        // First pass to get all ways that can be used by any transport mode. For every node, we
+        // count how often it is used by these ways, which tells intersections apart from nodes
+        // that only describe the shape of a way. Turn restrictions are collected as well, they
+        // are resolved to edges once the graph is complete.
         for obj in pbf.par_iter().map(Result::unwrap) {
+            if obj.is_relation() && obj.tags().get("type").map(|v| v.as_str()) == Some("restriction") {
+                osm_restrictions.extend(turn_restrictions(obj.relation().unwrap(), &restriction_classes));
+            }
+
             // In this version of e-route, we simply collect all ways that have the "highway"
-            // tag. They are then passed to the Gluon function to determine the edge weight.
+            // tag. Their tags are then passed to the Gluon functions to determine whether they
+            // are used, and what their edge weights are.
             if obj.is_way() && obj.tags().contains_key("highway") {
                 let highway_tag = obj.tags().get("highway").unwrap();
-                if edge_valid.call((highway_tag.to_string())).unwrap() {
-                    // Get all the references to other ways.
-                    for node in obj.way().unwrap().clone().nodes.windows(2) {
-                        edges.push(Edge {
-                            source: node[0].0,
-                            target: node[1].0,
-                            weight: 1.0,
-                            highway_tag: highway_tag.to_string()
-                        });
-                        // TODO For now, we simply flip the edge so we can still keep directed edges.
-                        // Actually, this doesn't work with Bellman-Ford.
-                        edges.push(Edge {
-                            source: node[1].0,
-                            target: node[0].0,
-                            weight: 1.0,
-                            highway_tag: highway_tag.to_string()
-                        });
-                        important_nodes.insert(node[0].0);
-                        important_nodes.insert(node[1].0);
+                let tags: GluonEdge = obj.tags().iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect();
+
+                // Every transport mode decides on its own whether it can use the way, and in
+                // which directions. The directions permitted by the tags are passed to the Gluon
+                // script, which has the final say for each direction.
+                let mut forward = Vec::with_capacity(transport_modes.len());
+                let mut backward = Vec::with_capacity(transport_modes.len());
+                for mode in transport_modes.iter_mut() {
+                    let (mut forward_valid, mut backward_valid) = (false, false);
+                    if mode.edge_valid.call(tags.clone()).unwrap() {
+                        let (fwd, bwd) = way_directions(obj.tags(), &mode.oneway_key);
+                        forward_valid = mode.edge_direction_valid
+                            .call(tags.clone(), true, fwd)
+                            .unwrap();
+                        backward_valid = mode.edge_direction_valid
+                            .call(tags.clone(), false, bwd)
+                            .unwrap();
                     }
+                    forward.push(forward_valid);
+                    backward.push(backward_valid);
+                }
+                let way_node_ids = obj.way().unwrap().nodes.iter().map(|n| n.0).collect::<Vec<_>>();
+                if !forward.iter().chain(backward.iter()).any(|&valid| valid) || way_node_ids.len() < 2 {
+                    continue;
                 }
+
+                for &node in &way_node_ids {
+                    *node_uses.entry(node).or_insert(0) += 1;
+                }
+                ways.push(UsableWay {
+                    id: obj.way().unwrap().id.0,
+                    tags: tags,
+                    nodes: way_node_ids,
+                    forward: forward,
+                    backward: backward,
+                    highway_tag: highway_tag.to_string(),
+                });
             }
         }
 
-        // Second pass to get all nodes.
+        // Second pass to get all nodes of these ways. Each node gets its elevation from the DEM;
+        // nodes outside of the DEM are assumed to lie at sea level. Charging stations are
+        // collected as well, they are linked to the graph when routing.
+        let mut nodes_outside_dem = 0;
         pbf.rewind().unwrap();
         for obj in pbf.par_iter().map(Result::unwrap) {
-            if obj.is_node() && important_nodes.contains(&obj.id().node().unwrap().0) {
+            if obj.is_node() && obj.tags().get("amenity").map(|v| v.as_str()) == Some("charging_station") {
+                let node = obj.node().unwrap();
+                charging_stations.push(charging_station(obj.id().node().unwrap().0, node.lon(), node.lat(),
+                                                        obj.tags()));
+            }
+            if obj.is_node() && node_uses.contains_key(&obj.id().node().unwrap().0) {
                 let node_id = obj.id().node().unwrap().0;
-                node_map.insert(node_id, nodes.len() as i64);
-                nodes.push(Node {
+                let lon = obj.node().unwrap().lon();
+                let lat = obj.node().unwrap().lat();
+                let elevation = match dem.elevation_at(lon, lat) {
+                    Some(elevation) => elevation,
+                    None => {
+                        nodes_outside_dem += 1;
+                        0.0
+                    }
+                };
+                way_nodes.insert(node_id, Node {
                     id: node_id,
-                    lon: obj.node().unwrap().lon(),
-                    lat: obj.node().unwrap().lat()
+                    lon: lon,
+                    lat: lat,
+                    elevation: elevation
                 });
             }
         }
+        info!(target: "graph::builder", "Found {} charging stations.", charging_stations.len());
+        if nodes_outside_dem > 0 {
+            warn!(target: "graph::builder", "{} nodes lie outside of the DEM, their elevation is set to 0.",
+                  nodes_outside_dem);
+        }
+
+        // Finally, split every way into edges at its ends, at intersections, and at peaks and
+        // valleys (so that the slopes of the edges still follow the DEM). All nodes in between
+        // are collapsed into the geometry of the edges. The costs of an edge are the sum of the
+        // costs of its segments, as computed by every transport mode that can use it.
+        let mut collapsed = 0;
+        for way in &ways {
+            let points = way.nodes.iter()
+                .filter_map(|id| way_nodes.get(id))
+                .collect::<Vec<_>>();
+            collapsed += split_way(way, &points, &node_uses, &mut node_map, &mut nodes, &mut edges,
+                                   |valid, piece| {
+                                       segment_costs(&mut transport_modes, valid, &way.tags, piece)
+                                   });
+        }
+        info!(target: "graph::builder", "Collapsed {} nodes into the geometry of {} edges.",
+              collapsed, edges.len());
+
+        // Islands (e.g., private parking lots or disconnected footways) cannot be reached from
+        // the rest of the graph, or cannot be left, so routes snapped to them would be empty.
+        // Every transport mode is therefore restricted to its largest strongly connected
+        // component, which is the only one snapping can choose.
+        let (nodes, edges) = keep_largest_components(nodes, edges, &modes);
+        info!(target: "graph::builder", "Built graph having {} nodes and {} edges.", nodes.len(), edges.len());
+
+        let turn_restrictions = resolve_restrictions(&osm_restrictions, &nodes, &edges);
+        info!(target: "graph::builder", "Resolved {} turn restrictions to {} restricted edge sequences.",
+              osm_restrictions.len(), turn_restrictions.len());
+        let turn_costs = turn_costs(&mut transport_modes, &modes, &ways, &nodes, &edges);
+        info!(target: "graph::builder", "Found {} turns having costs.", turn_costs.len());
 
-        // Finally, re-align node ids in edges.
-        for edge in &mut edges {
-            let new_source = node_map.get(&(edge.source as i64)).unwrap();
-            let new_target = node_map.get(&(edge.target as i64)).unwrap();
-            edge.source = *new_source;
-            edge.target = *new_target;
-            let source_node = &nodes[edge.source as usize];
-            let target_node = &nodes[edge.target as usize];
-            edge.weight = edge_weight
-                .call((&edge.highway_tag).to_string(), source_node.dist_to(target_node),
-                      (source_node.lon, source_node.lat),
-                      (target_node.lon, target_node.lat))
-                .unwrap() as f32;
+        SerializableGraph {
+            edges: edges,
+            nodes: nodes,
+            modes: modes,
+            hierarchies: Vec::new(),
+            charging_stations: charging_stations,
+            turn_restrictions: turn_restrictions,
+            turn_costs: turn_costs,
         }
+    }
+}
+
+/// Returns the index of a node in the graph, adding the node to the graph if necessary.
+fn graph_node(node: &Node, node_map: &mut HashMap<i64, i64>, nodes: &mut Vec<Node>) -> i64 {
+    *node_map.entry(node.id).or_insert_with(|| {
+        nodes.push(node.clone());
+        (nodes.len() - 1) as i64
+    })
+}
+
+/// Checks whether a node of a way is a peak or a valley, i.e., whether the way stops climbing
+/// and starts descending there (or vice versa), given the previous and the next node.
+fn elevation_breakpoint(previous: &Node, node: &Node, next: &Node) -> bool {
+    let before = node.elevation - previous.elevation;
+    let after = next.elevation - node.elevation;
+    (before > ELEVATION_TOLERANCE && after < -ELEVATION_TOLERANCE) ||
+        (before < -ELEVATION_TOLERANCE && after > ELEVATION_TOLERANCE)
+}
+
+/// Splits a way (given by its nodes) into edges at its ends, at intersections (i.e., nodes used by
+/// more than one way), and at peaks and valleys, and adds them in every direction some transport
+/// mode can use. The nodes in between are collapsed into the geometry of the edges. The costs of
+/// an edge are computed from the permitted transport modes and its nodes (in the direction it is
+/// traversed) by `costs`. Returns the number of collapsed nodes.
+fn split_way<F>(way: &UsableWay, points: &[&Node], node_uses: &HashMap<i64, usize>,
+                node_map: &mut HashMap<i64, i64>, nodes: &mut Vec<Node>, edges: &mut Vec<Edge>,
+                mut costs: F) -> usize
+    where F: FnMut(&[bool], &[&Node]) -> Vec<Option<Costs>> {
+    let mut collapsed = 0;
+    let mut start = 0;
+    for i in 1..points.len() {
+        if i < points.len() - 1 && node_uses[&points[i].id] == 1 &&
+            !elevation_breakpoint(points[i - 1], points[i], points[i + 1]) {
+            collapsed += 1;
+            continue;
+        }
+
+        let piece = &points[start..i + 1];
+        let source = graph_node(piece[0], node_map, nodes);
+        let target = graph_node(piece[piece.len() - 1], node_map, nodes);
+        if way.forward.iter().any(|&valid| valid) {
+            let forward_costs = costs(&way.forward, piece);
+            edges.push(way_edge(piece, source, target, forward_costs, way));
+        }
+        if way.backward.iter().any(|&valid| valid) {
+            let reversed = piece.iter().rev().cloned().collect::<Vec<_>>();
+            let backward_costs = costs(&way.backward, &reversed);
+            edges.push(way_edge(&reversed, target, source, backward_costs, way));
+        }
+        start = i;
+    }
+
+    collapsed
+}
+
+/// Computes the costs of traversing the given nodes of a way for every transport mode that can
+/// do so, as the sum of the costs of the segments between them.
+fn segment_costs(transport_modes: &mut [TransportMode], valid: &[bool], tags: &GluonEdge,
+                 points: &[&Node]) -> Vec<Option<Costs>> {
+    let mut costs = Vec::with_capacity(transport_modes.len());
+    for (mode, &valid) in transport_modes.iter_mut().zip(valid.iter()) {
+        if !valid {
+            costs.push(None);
+            continue;
+        }
+        costs.push(Some(sum_segments(points, |source, target| {
+            let gluon_costs = mode.edge_costs
+                .call(tags.clone(), source.dist_to(target),
+                      (source.lon, source.lat, source.elevation),
+                      (target.lon, target.lat, target.elevation))
+                .unwrap();
+            Costs {
+                distance: gluon_costs.distance as f32,
+                duration: gluon_costs.duration as f32,
+                energy: gluon_costs.energy as f32,
+            }
+        })));
+    }
+
+    costs
+}
 
-        SerializableGraph { edges: edges, nodes: nodes }
+/// Sums up the costs of the segments between the given nodes of a way, as computed by
+/// `segment_cost` for every segment.
+fn sum_segments<F>(points: &[&Node], mut segment_cost: F) -> Costs
+    where F: FnMut(&Node, &Node) -> Costs {
+    points.windows(2)
+        .fold(Costs::zero(), |total, segment| total + segment_cost(segment[0], segment[1]))
+}
+
+/// Creates an edge along the given nodes of a way, from the source to the target (given as
+/// indices of the graph nodes). The nodes in between become the geometry of the edge. Its length
+/// and slope (from which the energy of a vehicle profile is computed at query time) cover all
+/// nodes.
+fn way_edge(points: &[&Node], source: i64, target: i64, costs: Vec<Option<Costs>>,
+            way: &UsableWay) -> Edge {
+    let length = points.windows(2).map(|s| s[0].dist_to(s[1])).sum::<f64>();
+    let (first, last) = (points[0], points[points.len() - 1]);
+    Edge {
+        source: source,
+        target: target,
+        costs: costs,
+        length: length as f32,
+        slope: if length > 0.0 {
+            ((last.elevation - first.elevation) / (length * 1000.0)) as f32
+        } else {
+            0.0
+        },
+        geometry: points[1..points.len() - 1].iter().map(|n| (n.lon, n.lat)).collect(),
+        way: way.id,
+        highway_tag: way.highway_tag.clone(),
+    }
+}
+
+/// Computes the costs of all turns from one edge onto another (at the node they share) for the
+/// transport modes whose script has a `turn_cost` function, given the tags of both ways and the
+/// turn angle. As the turn-aware search relies on non-negative costs, negative costs returned by
+/// a script are clamped to 0. Turns without costs for any transport mode are left out.
+fn turn_costs(transport_modes: &mut [TransportMode], modes: &[String], ways: &[UsableWay], nodes: &[Node],
+              edges: &[Edge]) -> Vec<TurnCost> {
+    if transport_modes.iter().all(|m| m.turn_cost.is_none()) {
+        return Vec::new();
+    }
+    let way_index: HashMap<i64, usize> = ways.iter().enumerate().map(|(i, w)| (w.id, i)).collect();
+    let (offsets, out_edges) = build_adjacency(nodes.len(), edges.iter().map(|e| e.source as usize));
+
+    let mut turn_costs = Vec::new();
+    let mut clamped = vec![0; transport_modes.len()];
+    for (from, from_edge) in edges.iter().enumerate() {
+        let node = from_edge.target as usize;
+        for &to in &out_edges[offsets[node]..offsets[node + 1]] {
+            let to_edge = &edges[to];
+            let angle = turn_angle(nodes, from_edge, to_edge);
+            let mut costs = Vec::with_capacity(transport_modes.len());
+            for (mode, transport_mode) in transport_modes.iter_mut().enumerate() {
+                let mut cost = None;
+                if let Some(ref mut turn_cost) = transport_mode.turn_cost {
+                    if from_edge.costs[mode].is_some() && to_edge.costs[mode].is_some() {
+                        let gluon_costs = turn_cost
+                            .call(ways[way_index[&from_edge.way]].tags.clone(),
+                                  ways[way_index[&to_edge.way]].tags.clone(), angle)
+                            .unwrap();
+                        if gluon_costs.distance < 0.0 || gluon_costs.duration < 0.0 || gluon_costs.energy < 0.0 {
+                            clamped[mode] += 1;
+                        }
+                        cost = Some(Costs {
+                            distance: gluon_costs.distance.max(0.0) as f32,
+                            duration: gluon_costs.duration.max(0.0) as f32,
+                            energy: gluon_costs.energy.max(0.0) as f32,
+                        });
+                    }
+                }
+                costs.push(cost);
+            }
+            if costs.iter().any(|c| c.map_or(false, |c| c != Costs::zero())) {
+                turn_costs.push(TurnCost { from: from, to: to, costs: costs });
+            }
+        }
+    }
+    for (name, &count) in modes.iter().zip(&clamped) {
+        if count > 0 {
+            warn!(target: "graph::builder", "The turn_cost function of {} returned negative costs for {} turns, \
+                  they are set to 0.", name, count);
+        }
+    }
+
+    turn_costs
+}
+
+/// Computes the angle (in degrees) of the turn from one edge onto another at the node they
+/// share, from the last segment of the first edge and the first segment of the second one. The
+/// angle is 0 when going straight on, positive when turning right, negative when turning left,
+/// and 180 for a U-turn.
+fn turn_angle(nodes: &[Node], from: &Edge, to: &Edge) -> f64 {
+    let location = |n: &Node| (n.lon, n.lat);
+    let node = location(&nodes[from.target as usize]);
+    let before = from.geometry.last().cloned().unwrap_or_else(|| location(&nodes[from.source as usize]));
+    let after = to.geometry.first().cloned().unwrap_or_else(|| location(&nodes[to.target as usize]));
+
+    // The bearings are measured clockwise from north, on a plane around the node.
+    let scale = node.1.to_radians().cos();
+    let bearing = |a: (f64, f64), b: (f64, f64)| ((b.0 - a.0) * scale).atan2(b.1 - a.1).to_degrees();
+    let mut angle = bearing(node, after) - bearing(before, node);
+    if angle > 180.0 {
+        angle -= 360.0;
+    } else if angle <= -180.0 {
+        angle += 360.0;
+    }
+
+    angle
+}
+
+/// Reads the turn restrictions of a `type=restriction` relation, given the restriction classes
+/// of every transport mode. A `restriction:<class>` tag (e.g., `restriction:bicycle`) applies to
+/// the transport modes having that class, where the most specific class of a transport mode
+/// takes precedence. Otherwise, the `restriction` tag applies to all transport modes having any
+/// class, unless one of their classes is listed in the `except` tag. Only `no_*` and `only_*`
+/// restrictions are supported, and one restriction is returned per kind. Relations lacking a
+/// from way, a to way, or a via node or ways are skipped.
+fn turn_restrictions(relation: &Relation, restriction_classes: &[Vec<String>]) -> Vec<OsmRestriction> {
+    let tags = &relation.tags;
+    let except = tags.get("except")
+        .map(|v| v.split(';').map(|s| s.trim().to_string()).collect::<Vec<_>>())
+        .unwrap_or_else(Vec::new);
+    let mut kinds: Vec<(String, Vec<usize>)> = Vec::new();
+    for (mode, classes) in restriction_classes.iter().enumerate() {
+        let specific = classes.iter()
+            .filter_map(|class| tags.get(&format!("restriction:{}", class)[..]))
+            .next();
+        let kind = match specific {
+            Some(kind) => kind.to_string(),
+            None if !classes.is_empty() && !classes.iter().any(|class| except.contains(class)) =>
+                match tags.get("restriction") {
+                    Some(kind) => kind.to_string(),
+                    None => continue
+                },
+            None => continue
+        };
+        if !kind.starts_with("no_") && !kind.starts_with("only_") {
+            continue;
+        }
+        match kinds.iter().position(|k| k.0 == kind) {
+            Some(i) => kinds[i].1.push(mode),
+            None => kinds.push((kind, vec![mode]))
+        }
+    }
+
+    let (mut from, mut via_node, mut via_ways, mut to) = (Vec::new(), None, Vec::new(), Vec::new());
+    for member in &relation.refs {
+        match (member.role.as_str(), &member.member) {
+            ("from", &OsmId::Way(id)) => from.push(id.0),
+            ("via", &OsmId::Node(id)) => via_node = Some(id.0),
+            ("via", &OsmId::Way(id)) => via_ways.push(id.0),
+            ("to", &OsmId::Way(id)) => to.push(id.0),
+            _ => {}
+        }
+    }
+    if from.is_empty() || to.is_empty() || via_node.is_some() == !via_ways.is_empty() {
+        return Vec::new();
+    }
+
+    kinds.into_iter()
+        .map(|(kind, modes)| OsmRestriction {
+            from: from.clone(),
+            via_node: via_node,
+            via_ways: via_ways.clone(),
+            to: to.clone(),
+            only: kind.starts_with("only_"),
+            modes: modes,
+        })
+        .collect()
+}
+
+/// Determines in which directions a way may be traversed, according to its tags. Returns a
+/// tuple, containing whether the way may be traversed forward (in the order of its nodes) and
+/// backward. The mode-specific `oneway_key` (e.g., `oneway:bicycle`) takes precedence over
+/// `oneway`, and roundabouts and motorways are one-way unless tagged otherwise.
+fn way_directions(tags: &Tags, oneway_key: &str) -> (bool, bool) {
+    let oneway = tags.get(oneway_key).or_else(|| tags.get("oneway"));
+    match oneway.map(|v| v.as_str()) {
+        Some("yes") | Some("true") | Some("1") => (true, false),
+        Some("-1") | Some("reverse") => (false, true),
+        // Reversible ways change their direction over time, so we cannot use them at all.
+        Some("reversible") | Some("alternating") => (false, false),
+        Some("no") | Some("false") | Some("0") => (true, true),
+        _ => match (tags.get("junction").map(|v| v.as_str()), tags.get("highway").map(|v| v.as_str())) {
+            (Some("roundabout"), _) | (Some("circular"), _) => (true, false),
+            (_, Some("motorway")) | (_, Some("motorway_link")) => (true, false),
+            _ => (true, true)
+        }
+    }
+}
+
+/// Creates a charging station from the tags of an `amenity=charging_station` node. The socket
+/// types are taken from the `socket:*` keys (e.g., `socket:type2=2`), and the power is the
+/// maximum given by `charging_station:output`, `maxpower` or any `socket:*:output`.
+fn charging_station(id: i64, lon: f64, lat: f64, tags: &Tags) -> ChargingStation {
+    let mut sockets = Vec::new();
+    let mut power: Option<f32> = None;
+    for (key, value) in tags.iter() {
+        let parts = key.split(':').collect::<Vec<_>>();
+        match parts.as_slice() {
+            ["socket", socket] if value.as_str() != "no" && value.as_str() != "0" =>
+                sockets.push(socket.to_string()),
+            ["socket", _, "output"] | ["charging_station", "output"] | ["maxpower"] =>
+                for p in value.split(';').filter_map(parse_power) {
+                    power = Some(power.map_or(p, |max| max.max(p)));
+                },
+            _ => ()
+        }
+    }
+    sockets.sort();
+
+    ChargingStation {
+        id: id,
+        lon: lon,
+        lat: lat,
+        capacity: tags.get("capacity").and_then(|v| v.trim().parse::<u32>().ok()),
+        sockets: sockets,
+        power: power,
+    }
+}
+
+/// Parses a charging power such as `22 kW`, `22kW`, `11000 W` or `50` (in kW), returning it in kW.
+fn parse_power(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let (number, factor) = if value.ends_with("kW") {
+        (&value[..value.len() - 2], 1.0)
+    } else if value.ends_with("W") {
+        (&value[..value.len() - 1], 0.001)
+    } else {
+        (value, 1.0)
+    };
+    number.trim().parse::<f32>().ok()
+        .map(|power| power * factor)
+        .filter(|&power| power > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> Tags {
+        pairs.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn split_way_collapses_shape_nodes() {
+        // Node 12 is shared with another way, and node 14 is a peak, whereas nodes 11, 13 and 15
+        // only describe the shape of the way.
+        let elevations = [0.0, 1.0, 2.0, 3.0, 10.0, 5.0, 0.0];
+        let way_nodes = elevations.iter().enumerate()
+            .map(|(i, &elevation)| {
+                Node { id: 10 + i as i64, lon: 8.5 + i as f64 * 0.001, lat: 47.4, elevation: elevation }
+            })
+            .collect::<Vec<_>>();
+        let points = way_nodes.iter().collect::<Vec<_>>();
+        let node_uses: HashMap<i64, usize> = way_nodes.iter()
+            .map(|n| (n.id, if n.id == 12 { 2 } else { 1 }))
+            .collect();
+        let way = UsableWay {
+            id: 1,
+            tags: Vec::new(),
+            nodes: way_nodes.iter().map(|n| n.id).collect(),
+            forward: vec![true],
+            backward: vec![true],
+            highway_tag: String::from("residential"),
+        };
+
+        // Every segment takes a second, and needs energy according to its elevation difference.
+        let (mut node_map, mut nodes, mut edges) = (HashMap::new(), Vec::new(), Vec::new());
+        let collapsed = split_way(&way, &points, &node_uses, &mut node_map, &mut nodes, &mut edges,
+                                  |valid, piece| {
+            valid.iter().map(|_| Some(sum_segments(piece, |source, target| Costs {
+                distance: source.dist_to(target) as f32,
+                duration: 1.0,
+                energy: (target.elevation - source.elevation) as f32,
+            }))).collect()
+        });
+        assert_eq!(collapsed, 3);
+        assert_eq!(nodes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![10, 12, 14, 16]);
+        assert_eq!(edges.iter().map(|e| (e.source, e.target)).collect::<Vec<_>>(),
+                   vec![(0, 1), (1, 0), (1, 2), (2, 1), (2, 3), (3, 2)]);
+
+        let (forward, backward) = (&edges[2], &edges[3]);
+        assert_eq!(forward.geometry, vec![(way_nodes[3].lon, way_nodes[3].lat)]);
+        assert_eq!(backward.geometry, forward.geometry);
+        let costs = forward.costs[0].unwrap();
+        assert_eq!(costs.duration, 2.0);
+        assert_eq!(costs.energy, 8.0);
+        assert_eq!(backward.costs[0].unwrap().energy, -8.0);
+        let length = way_nodes[2].dist_to(&way_nodes[3]) + way_nodes[3].dist_to(&way_nodes[4]);
+        assert!((costs.distance as f64 - length).abs() < 1e-6);
+        assert!((forward.length as f64 - length).abs() < 1e-6);
+        assert!((forward.slope as f64 - 8.0 / (length * 1000.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn way_directions_from_tags() {
+        let cases = vec![
+            (vec![], (true, true)),
+            (vec![("oneway", "yes")], (true, false)),
+            (vec![("oneway", "true")], (true, false)),
+            (vec![("oneway", "1")], (true, false)),
+            (vec![("oneway", "-1")], (false, true)),
+            (vec![("oneway", "reverse")], (false, true)),
+            (vec![("oneway", "reversible")], (false, false)),
+            (vec![("oneway", "alternating")], (false, false)),
+            (vec![("oneway", "no")], (true, true)),
+            (vec![("junction", "roundabout")], (true, false)),
+            (vec![("junction", "circular")], (true, false)),
+            (vec![("junction", "roundabout"), ("oneway", "no")], (true, true)),
+            (vec![("highway", "motorway")], (true, false)),
+            (vec![("highway", "motorway_link")], (true, false)),
+            (vec![("highway", "motorway"), ("oneway", "no")], (true, true)),
+            (vec![("highway", "primary")], (true, true)),
+            (vec![("oneway", "yes"), ("oneway:bicycle", "no")], (true, true)),
+            (vec![("oneway:bicycle", "-1")], (false, true)),
+        ];
+        for (pairs, expected) in cases {
+            assert_eq!(way_directions(&tags(&pairs), "oneway:bicycle"), expected, "{:?}", pairs);
+        }
     }
 }
@@ -0,0 +1,548 @@
+use std;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use graph::core::{Graph, Costs, Metric, State, Weighting, RoutingError};
+
+/// The resolution (in percent of the battery capacity) of the charge up to which a vehicle may
+/// charge at a charging station, besides the charge needed for the next leg.
+const CHARGING_RESOLUTION: usize = 1;
+
+/// The maximal distance (in kilometers) between a charging station and the node it is linked to.
+const MAX_STATION_DISTANCE: f64 = 0.2;
+
+/// The maximal (Haversine) distance in kilometers between two charging stations connected by a
+/// precomputed leg. Longer trips pass further charging stations, which they may leave without
+/// charging.
+const MAX_LEG_DISTANCE: f64 = 150.0;
+
+/// A charging station, as extracted from the `amenity=charging_station` nodes of OSM.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChargingStation {
+    /// The OSM id of this charging station.
+    pub id: i64,
+    /// The longitude of this charging station.
+    pub lon: f64,
+    /// The latitude of this charging station.
+    pub lat: f64,
+    /// The number of vehicles that can charge at the same time, if known.
+    pub capacity: Option<u32>,
+    /// The types of the sockets, e.g., `type2` or `chademo`.
+    pub sockets: Vec<String>,
+    /// The maximal charging power in kW, if known.
+    pub power: Option<f32>,
+}
+
+/// The charging power curve of a vehicle, i.e., the maximal charging power (in kW) depending on
+/// the state of charge (in percent), which is interpolated linearly between the given points.
+#[derive(Debug, Clone)]
+pub struct ChargingCurve {
+    /// The points of the curve, as (state of charge, power), ordered by the state of charge.
+    points: Vec<(f32, f32)>,
+}
+
+/// A stop at a charging station along a route.
+#[derive(Debug, Clone)]
+pub struct ChargingStop {
+    /// The index of the charging station in the graph.
+    pub station: usize,
+    /// The charge of the battery (in kWh) when arriving at the charging station.
+    pub arrival_charge: f32,
+    /// The charge of the battery (in kWh) when leaving the charging station.
+    pub departure_charge: f32,
+    /// The time spent charging, in seconds.
+    pub duration: f32,
+}
+
+/// The charging stations linked to the graph for a transport mode, and the legs between them,
+/// which are precomputed when the graph is loaded.
+#[derive(Debug, Clone)]
+pub struct ChargingNetwork {
+    /// The node every charging station is linked to, i.e., the closest node the transport mode
+    /// can use, or `None` if there is none within `MAX_STATION_DISTANCE`.
+    nodes: Vec<Option<usize>>,
+    /// The legs leaving every charging station towards the charging stations within
+    /// `MAX_LEG_DISTANCE`, without vehicle profile.
+    legs: Vec<Vec<Leg>>,
+}
+
+/// A path between two vertices of the charging graph (i.e., the charging stations, the source
+/// and the target), summarized by its effect on the charge of the battery, which does not depend
+/// on the capacity of the battery.
+#[derive(Debug, Copy, Clone)]
+struct Leg {
+    /// The vertex where this leg ends.
+    target: usize,
+    /// Whether this is the path needing the least energy, rather than the fastest path.
+    economical: bool,
+    /// The costs of this leg.
+    costs: Costs,
+    /// The minimal charge needed at the start of this leg to reach its end.
+    need: f32,
+    /// The maximal charge needed at any node of this leg to reach its end, which the battery has
+    /// to be able to hold.
+    peak: f32,
+    /// The maximal energy needed from any node of this leg (except its start) to its end. As the
+    /// battery cannot be charged beyond its capacity, the charge at the end of the leg is at most
+    /// the capacity minus this energy.
+    surplus: f32,
+}
+
+/// A label of the search over charging stops, i.e., a way of reaching a vertex of the
+/// charging graph with some charge.
+#[derive(Debug, Copy, Clone)]
+struct ChargingLabel {
+    /// The vertex that was reached.
+    vertex: usize,
+    /// The time (in seconds, including charging) at which the vertex was reached.
+    time: f32,
+    /// The charge (in kWh) with which the vertex was reached.
+    charge: f32,
+    /// The label from which this one was created and the leg leading from its vertex to this
+    /// one, or `None` for the source.
+    pred: Option<(usize, Leg)>,
+    /// The charge with which the vertex of the predecessor was left.
+    departure: f32,
+    /// The time spent charging at the vertex of the predecessor.
+    charging_time: f32,
+    /// Whether another label at the same vertex dominates this one.
+    dominated: bool,
+}
+
+impl ChargingCurve {
+    /// Parses a charging curve given as comma-separated `state of charge:power` pairs, e.g.,
+    /// `0:150,50:120,80:50,100:10`. Returns `None` if the curve is invalid.
+    pub fn parse(curve: &str) -> Option<ChargingCurve> {
+        let mut points = Vec::new();
+        for point in curve.split(',') {
+            let mut parts = point.split(':');
+            match (parts.next().and_then(|soc| soc.trim().parse::<f32>().ok()),
+                   parts.next().and_then(|power| power.trim().parse::<f32>().ok()), parts.next()) {
+                (Some(soc), Some(power), None) if power > 0.0 => points.push((soc, power)),
+                _ => return None
+            }
+        }
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+        Some(ChargingCurve { points: points })
+    }
+
+    /// The maximal charging power (in kW) at a state of charge (in percent).
+    pub fn power_at(&self, soc: f32) -> f32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        if soc <= first.0 {
+            return first.1;
+        }
+        for w in self.points.windows(2) {
+            if soc <= w[1].0 {
+                let fraction = (soc - w[0].0) / (w[1].0 - w[0].0);
+                return w[0].1 + fraction * (w[1].1 - w[0].1);
+            }
+        }
+        last.1
+    }
+
+    /// The time (in seconds) needed to charge a battery of the given capacity from one charge to
+    /// another (all in kWh), at a charging station delivering at most the given power (in kW).
+    pub fn charging_time(&self, from: f32, to: f32, capacity: f32, max_power: Option<f32>) -> f32 {
+        // The power changes with the state of charge, so we integrate over steps of at most
+        // one percent of the capacity.
+        let steps = ((to - from) / capacity * 100.0).ceil().max(0.0) as usize;
+        let mut time = 0.0;
+        for i in 0..steps {
+            let start = from + (to - from) * i as f32 / steps as f32;
+            let end = from + (to - from) * (i + 1) as f32 / steps as f32;
+            let power = self.power_at((start + end) / 2.0 / capacity * 100.0);
+            let power = match max_power {
+                Some(max_power) => power.min(max_power),
+                None => power
+            };
+            time += (end - start) / power * 3600.0;
+        }
+        time
+    }
+}
+
+impl Leg {
+    /// The charge at the end of this leg, given the charge at its start and the capacity of the
+    /// battery, or `None` if the battery would run empty.
+    fn arrival(&self, charge: f32, capacity: f32) -> Option<f32> {
+        if charge < self.need || self.peak > capacity {
+            None
+        } else {
+            Some((charge - self.costs.energy).min(capacity - self.surplus))
+        }
+    }
+}
+
+impl ChargingLabel {
+    /// Checks whether this label is at least as good as another one, i.e., reaches the vertex
+    /// at least as early and with at least as much charge.
+    fn dominates(&self, other: &ChargingLabel) -> bool {
+        self.time <= other.time && self.charge >= other.charge
+    }
+}
+
+/// Implementation of charging-aware routing.
+impl Graph {
+    /// Links the charging stations to the closest node every transport mode can use, and finds
+    /// the legs between them. This is done once when the graph is loaded, so that a routing
+    /// request only has to find the legs leaving the source and reaching the target.
+    pub fn link_charging_stations(&mut self) {
+        let mut networks = Vec::with_capacity(self.modes.len());
+        for mode in 0..self.modes.len() {
+            let nodes = self.charging_stations.iter()
+                .map(|station| self.snap(station.lon, station.lat, Some(MAX_STATION_DISTANCE), mode).ok())
+                .collect::<Vec<_>>();
+            let vertices = station_vertices(&nodes);
+            let weighting = Weighting { mode: mode, metric: Metric::Duration, vehicle: None };
+            let legs = nodes.iter().enumerate()
+                .map(|(station, &node)| match node {
+                    Some(node) => self.station_legs(station, node, weighting, &vertices),
+                    None => Vec::new()
+                })
+                .collect::<Vec<_>>();
+            info!(target: "graph::reader", "Linked {} of {} charging stations for '{}', found {} legs.",
+                  vertices.values().map(Vec::len).sum::<usize>(), nodes.len(), self.modes[mode],
+                  legs.iter().map(Vec::len).sum::<usize>());
+            networks.push(ChargingNetwork { nodes: nodes, legs: legs });
+        }
+        self.charging_networks = networks;
+    }
+
+    /// Performs a routing request from source to target (given as internal ids) for an electric
+    /// vehicle (using the transport mode and vehicle profile of the weighting) with the given
+    /// battery capacity and initial charge (in kWh), which may stop at charging stations along the
+    /// way. This searches the charging graph, whose vertices are the charging stations linked by
+    /// `link_charging_stations`, the source and the target, and whose edges (the legs) are the
+    /// fastest paths and the paths needing the least energy between them. The labels of the search
+    /// are pairs of a vertex and a charge, which are settled in the order of their time plus a
+    /// lower bound of the time needed to reach the target, so the search stays in the corridor
+    /// between source and target. At every charging station, the vehicle may continue without
+    /// charging, charge just enough for one of the legs leaving it, or charge up to any multiple
+    /// of `CHARGING_RESOLUTION` percent of the capacity, as fast as the charging curve and the
+    /// station allow. The charging stops found are optimal for these options and legs. Returns
+    /// the trace, the costs of driving, the charging stops, and the charge remaining at the target.
+    /// If the target cannot be reached, the trace is empty.
+    pub fn charging_route(&self, source: usize, target: usize, weighting: Weighting, capacity: f32,
+                          initial_charge: f32, curve: &ChargingCurve)
+                          -> Result<(Vec<Vec<f64>>, Costs, Vec<ChargingStop>, f32), RoutingError> {
+        let unlinked = ChargingNetwork { nodes: Vec::new(), legs: Vec::new() };
+        let network = self.charging_networks.get(weighting.mode).unwrap_or(&unlinked);
+        let (source_vertex, target_vertex) = (network.nodes.len(), network.nodes.len() + 1);
+        let mut vertex_nodes = network.nodes.clone();
+        vertex_nodes.push(Some(source));
+        vertex_nodes.push(Some(target));
+        let vertices = station_vertices(&network.nodes);
+        let mut source_vertices = vertices.clone();
+        source_vertices.entry(target).or_insert_with(Vec::new).push(target_vertex);
+
+        // No path can need less energy per kilometer (of Haversine distance) than the most
+        // economical edge, which bounds the range of the vehicle on a single charge (unless it
+        // can recuperate energy).
+        let min_energy_per_km = self.lower_bound_per_km(Weighting { metric: Metric::Energy, ..weighting });
+        let range = if min_energy_per_km > 0.0 { (capacity / min_energy_per_km) as f64 } else { std::f64::MAX };
+        let min_duration_per_km = self.lower_bound_per_km(Weighting { metric: Metric::Duration, ..weighting });
+
+        // The legs leaving the source and reaching the target are found for every request, and so
+        // are the legs between charging stations for a vehicle profile, once they are needed.
+        let mut extra_legs = vec![Vec::new(); target_vertex + 1];
+        extra_legs[source_vertex] = self.legs(source, weighting, true, range, &source_vertices);
+        for leg in self.legs(target, weighting, false, range, &vertices) {
+            extra_legs[leg.target].push(Leg { target: target_vertex, ..leg });
+        }
+        let mut vehicle_legs: Vec<Option<Vec<Leg>>> = vec![None; source_vertex];
+
+        let mut labels = Vec::new();
+        let mut bags = vec![Vec::new(); target_vertex + 1];
+        let mut heap = BinaryHeap::new();
+        let initial_charge = initial_charge.min(capacity);
+        let bound = |vertex: usize| {
+            vertex_nodes[vertex].map_or(0.0, |node| {
+                (self.nodes[node].dist_to(&self.nodes[target]) * min_duration_per_km as f64) as f32
+            })
+        };
+        add_charging_label(ChargingLabel {
+            vertex: source_vertex,
+            time: 0.0,
+            charge: initial_charge,
+            pred: None,
+            departure: initial_charge,
+            charging_time: 0.0,
+            dominated: false,
+        }, bound(source_vertex), &mut labels, &mut bags, &mut heap);
+
+        // The first label reaching the target is the fastest one, as the lower bound never
+        // overestimates the time needed.
+        let mut result = None;
+        while let Some(State { position, .. }) = heap.pop() {
+            if labels[position].dominated { continue; }
+            let label = labels[position];
+            if label.vertex == target_vertex {
+                result = Some(position);
+                break;
+            }
+
+            let is_station = label.vertex < source_vertex;
+            if is_station && weighting.vehicle.is_some() && vehicle_legs[label.vertex].is_none() {
+                let node = vertex_nodes[label.vertex].unwrap();
+                vehicle_legs[label.vertex] = Some(self.station_legs(label.vertex, node, weighting, &vertices));
+            }
+            let station_legs: &[Leg] = if !is_station {
+                &[]
+            } else {
+                match vehicle_legs[label.vertex] {
+                    Some(ref legs) => &legs[..],
+                    None => &network.legs[label.vertex][..]
+                }
+            };
+            let legs = station_legs.iter().chain(extra_legs[label.vertex].iter()).collect::<Vec<_>>();
+
+            // The vehicle either leaves with the charge it arrived with, or charges first.
+            let mut departures = vec![label.charge];
+            if is_station {
+                departures.extend(legs.iter()
+                    .map(|leg| leg.need)
+                    .filter(|&need| need > label.charge && need <= capacity));
+                for step in 1..(100 / CHARGING_RESOLUTION + 1) {
+                    let charge = capacity * (step * CHARGING_RESOLUTION) as f32 / 100.0;
+                    if charge > label.charge {
+                        departures.push(charge);
+                    }
+                }
+                departures.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+                departures.dedup();
+            }
+
+            let power = if is_station { self.charging_stations[label.vertex].power } else { None };
+            let (mut charged, mut charging_time) = (label.charge, 0.0);
+            for &departure in &departures {
+                charging_time += curve.charging_time(charged, departure, capacity, power);
+                charged = departure;
+                for &leg in &legs {
+                    if let Some(arrival) = leg.arrival(departure, capacity) {
+                        add_charging_label(ChargingLabel {
+                            vertex: leg.target,
+                            time: label.time + charging_time + leg.costs.duration,
+                            charge: arrival,
+                            pred: Some((position, *leg)),
+                            departure: departure,
+                            charging_time: charging_time,
+                            dominated: false,
+                        }, bound(leg.target), &mut labels, &mut bags, &mut heap);
+                    }
+                }
+            }
+        }
+
+        let result = match result {
+            Some(result) => result,
+            None => return Ok((Vec::new(), Costs::zero(), Vec::new(), 0.0))
+        };
+
+        // Backtrack the labels, and expand their legs to the paths through the graph by searching
+        // them again, only from the vertices that are part of the route.
+        let mut chain = Vec::new();
+        let mut current = result;
+        while let Some((pred, leg)) = labels[current].pred {
+            chain.push((current, pred, leg));
+            current = pred;
+        }
+        chain.reverse();
+
+        let mut edges = Vec::new();
+        let mut costs = Costs::zero();
+        let mut stops = Vec::new();
+        for &(l, pred, leg) in &chain {
+            let (label, pred) = (labels[l], labels[pred]);
+            if label.charging_time > 0.0 {
+                stops.push(ChargingStop {
+                    station: pred.vertex,
+                    arrival_charge: pred.charge,
+                    departure_charge: label.departure,
+                    duration: label.charging_time,
+                });
+            }
+
+            let (start, end) = (vertex_nodes[pred.vertex].unwrap(), vertex_nodes[leg.target].unwrap());
+            let path = if pred.vertex == source_vertex {
+                self.leg_path(source, end, weighting, true, range, leg.economical)
+            } else if leg.target == target_vertex {
+                self.leg_path(target, start, weighting, false, range, leg.economical)
+            } else {
+                self.leg_path(start, end, weighting, true, MAX_LEG_DISTANCE, leg.economical)
+            };
+            edges.extend(path);
+            costs = costs + leg.costs;
+        }
+        println!(" ˪— Found route with {} charging stops. Total time: {}s.", stops.len(), labels[result].time);
+
+        Ok((self.edges_trace(source, &edges), costs, stops, labels[result].charge))
+    }
+
+    /// Finds the legs between a node and the vertices of the charging graph (given by their
+    /// nodes) within the given distance (in kilometers) of it, which lead from the node to the
+    /// vertices, or from the vertices to the node if `forward` is false. For every vertex, this is
+    /// the fastest path, and the path needing the least energy if it differs. The legs end at the
+    /// vertices they were found for, and the caller has to fix this for backward legs.
+    fn legs(&self, root: usize, weighting: Weighting, forward: bool, radius: f64,
+            vertices: &HashMap<usize, Vec<usize>>) -> Vec<Leg> {
+        let fastest_weighting = Weighting { metric: Metric::Duration, ..weighting };
+        let economical_weighting = Weighting { metric: Metric::Energy, ..weighting };
+        let fastest = self.bounded_tree(root, fastest_weighting, forward, radius);
+        let economical = self.bounded_tree(root, economical_weighting, forward, radius);
+        let mut legs = Vec::new();
+        for (&node, node_vertices) in vertices {
+            if node != root && (fastest[node].is_none() || economical[node].is_none()) {
+                continue;
+            }
+            let fastest_edges = self.tree_path(&fastest, node, forward);
+            let economical_edges = self.tree_path(&economical, node, forward);
+            for &vertex in node_vertices {
+                legs.push(self.leg(vertex, &fastest_edges, false, weighting));
+                if economical_edges != fastest_edges {
+                    legs.push(self.leg(vertex, &economical_edges, true, weighting));
+                }
+            }
+        }
+
+        legs
+    }
+
+    /// Finds the legs from a charging station (linked to the given node) to the other charging
+    /// stations within `MAX_LEG_DISTANCE`.
+    fn station_legs(&self, station: usize, node: usize, weighting: Weighting,
+                    vertices: &HashMap<usize, Vec<usize>>) -> Vec<Leg> {
+        self.legs(node, weighting, true, MAX_LEG_DISTANCE, vertices).into_iter()
+            .filter(|leg| leg.target != station)
+            .collect()
+    }
+
+    /// Finds the edges of a leg found by `legs` again, given the node it was searched from, the
+    /// node of the vertex it was found for, and whether it is the path needing the least energy.
+    fn leg_path(&self, root: usize, node: usize, weighting: Weighting, forward: bool, radius: f64,
+                economical: bool) -> Vec<usize> {
+        let metric = if economical { Metric::Energy } else { Metric::Duration };
+        let tree = self.bounded_tree(root, Weighting { metric: metric, ..weighting }, forward, radius);
+        self.tree_path(&tree, node, forward)
+    }
+
+    /// Summarizes the path along the given edges as a leg ending at a vertex of the charging
+    /// graph.
+    fn leg(&self, target: usize, edges: &[usize], economical: bool, weighting: Weighting) -> Leg {
+        let costs = edges.iter().map(|&e| self.costs_for(e, weighting).unwrap()).collect::<Vec<_>>();
+
+        // The charge needed is found backwards, as energy recuperated later on does not help.
+        let (mut need, mut peak, mut remaining, mut surplus) = (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+        for c in costs.iter().rev() {
+            surplus = surplus.max(remaining);
+            remaining += c.energy;
+            need = (need + c.energy).max(0.0);
+            peak = peak.max(need);
+        }
+
+        Leg {
+            target: target,
+            economical: economical,
+            costs: costs.iter().fold(Costs::zero(), |total, &c| total + c),
+            need: need,
+            peak: peak,
+            surplus: surplus,
+        }
+    }
+
+    /// Searches the paths minimizing the metric of the weighting from a node to all others, or
+    /// from all others to the node if `forward` is false. Nodes further away than the given
+    /// distance (in kilometers) are not reached, which limits the search to the surroundings of
+    /// the node. As the energy may be negative, nodes are expanded again whenever they are
+    /// reached at lower costs, which is limited by the number of nodes in case of negative cycles.
+    /// Returns the edge through which every node was reached.
+    fn bounded_tree(&self, root: usize, weighting: Weighting, forward: bool, radius: f64)
+                    -> Vec<Option<usize>> {
+        let nodes_count = self.nodes.len();
+        let mut pred = vec![None; nodes_count];
+        let mut dist = vec![std::f32::MAX; nodes_count];
+        let mut updates = vec![0; nodes_count];
+        let mut heap = BinaryHeap::new();
+        dist[root] = 0.0;
+        heap.push(State { cost: 0.0, position: root });
+
+        while let Some(State { cost, position }) = heap.pop() {
+            if cost > dist[position] { continue; }
+
+            let edges = if forward { self.outgoing(position) } else { self.incoming(position) };
+            for &e in edges {
+                let weight = match self.weight(e, weighting) {
+                    Some(weight) => weight,
+                    None => continue
+                };
+                let next = (if forward { self.edges[e].target } else { self.edges[e].source }) as usize;
+                if next != root && cost + weight < dist[next] && updates[next] < nodes_count &&
+                    self.nodes[next].dist_to(&self.nodes[root]) <= radius {
+                    dist[next] = cost + weight;
+                    pred[next] = Some(e);
+                    updates[next] += 1;
+                    heap.push(State { cost: dist[next], position: next });
+                }
+            }
+        }
+
+        pred
+    }
+
+    /// Backtracks from a node in a tree given by the edge through which every node was reached,
+    /// which leads away from the root of the tree if `forward` is true, and towards it otherwise.
+    /// Returns the edges of the path between the root and the node, in the order they are driven.
+    fn tree_path(&self, pred: &[Option<usize>], node: usize, forward: bool) -> Vec<usize> {
+        let mut edges = Vec::new();
+        let mut current = node;
+        while let Some(e) = pred[current] {
+            edges.push(e);
+            current = (if forward { self.edges[e].source } else { self.edges[e].target }) as usize;
+
+            // Make sure this doesn't run forever.
+            if edges.len() > self.nodes.len() {
+                break;
+            }
+        }
+        if forward {
+            edges.reverse();
+        }
+        edges
+    }
+}
+
+/// Maps the nodes that charging stations are linked to to the indices of the charging stations,
+/// which are their vertices in the charging graph.
+fn station_vertices(nodes: &[Option<usize>]) -> HashMap<usize, Vec<usize>> {
+    let mut vertices = HashMap::new();
+    for (station, node) in nodes.iter().enumerate() {
+        if let Some(node) = *node {
+            vertices.entry(node).or_insert_with(Vec::new).push(station);
+        }
+    }
+    vertices
+}
+
+/// Adds a label to the labels of its vertex, unless one of them dominates it, in which case it
+/// is discarded. Labels dominated by the new one are marked, so that they are skipped once they
+/// are taken from the heap. Labels are taken from the heap in the order of their time plus the
+/// given lower bound of the time needed to reach the target from their vertex.
+fn add_charging_label(label: ChargingLabel, bound: f32, labels: &mut Vec<ChargingLabel>,
+                      bags: &mut Vec<Vec<usize>>, heap: &mut BinaryHeap<State>) {
+    let bag = &mut bags[label.vertex];
+    if bag.iter().any(|&l| labels[l].dominates(&label)) {
+        return;
+    }
+    for &l in bag.iter() {
+        if label.dominates(&labels[l]) {
+            labels[l].dominated = true;
+        }
+    }
+    bag.retain(|&l| !labels[l].dominated);
+
+    bag.push(labels.len());
+    heap.push(State { cost: label.time + bound, position: labels.len() });
+    labels.push(label);
+}
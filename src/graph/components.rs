@@ -0,0 +1,181 @@
+use std;
+
+use graph::core::{Node, Edge, build_adjacency};
+
+/// Computes the strongly connected components of the graph formed by all edges that can be used
+/// by a transport mode, using (an iterative version of) Tarjan's algorithm. Returns the
+/// component of every node, together with the number of components. Nodes the transport mode
+/// cannot use form components of their own.
+pub fn strongly_connected_components(nodes_count: usize, edges: &[Edge], mode: usize) -> (Vec<usize>, usize) {
+    let unvisited = std::usize::MAX;
+    let (offsets, out_edges) = build_adjacency(nodes_count, edges.iter().map(|e| e.source as usize));
+    let mut index = vec![unvisited; nodes_count];
+    let mut lowlink = vec![0; nodes_count];
+    let mut on_stack = vec![false; nodes_count];
+    let mut stack = Vec::new();
+    let mut component = vec![unvisited; nodes_count];
+    let mut next_index = 0;
+    let mut components = 0;
+
+    for root in 0..nodes_count {
+        if index[root] != unvisited {
+            continue;
+        }
+
+        // Instead of recursing, we keep the nodes being visited together with the position of
+        // the next outgoing edge to follow.
+        index[root] = next_index;
+        lowlink[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+        let mut calls = vec![(root, offsets[root])];
+        loop {
+            let (node, position) = match calls.last() {
+                Some(&call) => call,
+                None => break
+            };
+            if position < offsets[node + 1] {
+                let last = calls.len() - 1;
+                calls[last].1 += 1;
+                let edge = &edges[out_edges[position]];
+                if edge.costs[mode].is_none() {
+                    continue;
+                }
+                let next = edge.target as usize;
+                if index[next] == unvisited {
+                    index[next] = next_index;
+                    lowlink[next] = next_index;
+                    next_index += 1;
+                    stack.push(next);
+                    on_stack[next] = true;
+                    calls.push((next, offsets[next]));
+                } else if on_stack[next] {
+                    lowlink[node] = lowlink[node].min(index[next]);
+                }
+            } else {
+                calls.pop();
+                if let Some(&(parent, _)) = calls.last() {
+                    lowlink[parent] = lowlink[parent].min(lowlink[node]);
+                }
+                if lowlink[node] == index[node] {
+                    while let Some(member) = stack.pop() {
+                        on_stack[member] = false;
+                        component[member] = components;
+                        if member == node {
+                            break;
+                        }
+                    }
+                    components += 1;
+                }
+            }
+        }
+    }
+
+    (component, components)
+}
+
+/// Restricts every transport mode to the largest strongly connected component of the edges it
+/// can use, i.e., edges leading to, from or within smaller components (such as private parking
+/// lots or disconnected footways) get no costs for the transport mode. Afterwards, edges that no
+/// transport mode can use and nodes without edges are removed. Returns the remaining nodes and
+/// edges.
+pub fn keep_largest_components(nodes: Vec<Node>, mut edges: Vec<Edge>, modes: &[String])
+                               -> (Vec<Node>, Vec<Edge>) {
+    for (mode, name) in modes.iter().enumerate() {
+        let (component, components) = strongly_connected_components(nodes.len(), &edges, mode);
+        let mut sizes = vec![0; components];
+        for &c in &component {
+            sizes[c] += 1;
+        }
+        let main = match (0..components).max_by_key(|&c| sizes[c]) {
+            Some(main) => main,
+            None => continue
+        };
+
+        let mut removed = 0;
+        for edge in edges.iter_mut() {
+            if edge.costs[mode].is_some() &&
+                (component[edge.source as usize] != main || component[edge.target as usize] != main) {
+                edge.costs[mode] = None;
+                removed += 1;
+            }
+        }
+        info!(target: "graph::builder", "Kept the largest strongly connected component for {} ({} nodes), \
+              removing {} edges outside of it.", name, sizes[main], removed);
+    }
+
+    edges.retain(|e| e.costs.iter().any(Option::is_some));
+    let mut used = vec![false; nodes.len()];
+    for edge in &edges {
+        used[edge.source as usize] = true;
+        used[edge.target as usize] = true;
+    }
+    let mut node_ids = vec![0; nodes.len()];
+    let mut kept = Vec::new();
+    for (i, node) in nodes.into_iter().enumerate() {
+        if used[i] {
+            node_ids[i] = kept.len() as i64;
+            kept.push(node);
+        }
+    }
+    for edge in edges.iter_mut() {
+        edge.source = node_ids[edge.source as usize];
+        edge.target = node_ids[edge.target as usize];
+    }
+
+    (kept, edges)
+}
+
+#[cfg(test)]
+mod tests {
+    use spade::rtree::RTree;
+    use graph::core::{Graph, Costs};
+
+    use super::*;
+
+    fn edge(source: i64, target: i64, costs: Vec<Option<Costs>>) -> Edge {
+        Edge {
+            source: source,
+            target: target,
+            costs: costs,
+            length: 0.0,
+            slope: 0.0,
+            geometry: Vec::new(),
+            way: 0,
+            highway_tag: String::new(),
+        }
+    }
+
+    #[test]
+    fn keep_largest_components_of_every_mode() {
+        // The first mode can use the cycle of nodes 1, 2 and 3, and the one of nodes 4 and 5,
+        // while the second mode can only use the latter. Node 0 cannot be reached at all.
+        let nodes = (0..6).map(|i| Node { id: 100 + i, lon: 8.5 + i as f64 * 0.01, lat: 47.4, elevation: 0.0 })
+            .collect::<Vec<_>>();
+        let both = vec![Some(Costs::uniform(1.0)), Some(Costs::uniform(1.0))];
+        let first = vec![Some(Costs::uniform(1.0)), None];
+        let edges = vec![
+            edge(1, 2, first.clone()),
+            edge(2, 3, first.clone()),
+            edge(3, 1, first.clone()),
+            edge(4, 5, both.clone()),
+            edge(5, 4, both.clone()),
+            edge(0, 1, both.clone()),
+        ];
+        let modes = vec![String::from("first"), String::from("second")];
+
+        let (nodes, edges) = keep_largest_components(nodes, edges, &modes);
+        assert_eq!(nodes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![101, 102, 103, 104, 105]);
+        assert_eq!(edges.iter().map(|e| (e.source, e.target)).collect::<Vec<_>>(),
+                   vec![(0, 1), (1, 2), (2, 0), (3, 4), (4, 3)]);
+        assert_eq!(edges[0].costs, first);
+        assert_eq!(edges[3].costs, vec![None, Some(Costs::uniform(1.0))]);
+
+        let graph = Graph::new(nodes, edges, RTree::new(), modes);
+        for (i, node) in graph.nodes.iter().enumerate() {
+            assert_eq!(graph.osm_index[&node.id], i);
+        }
+        assert!(!graph.osm_index.contains_key(&100));
+    }
+}
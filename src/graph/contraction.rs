@@ -0,0 +1,415 @@
+use std;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use pbr::ProgressBar;
+
+use graph::core::{Edge, State, Weighting, build_adjacency};
+
+/// Limits the number of nodes a single witness search settles. Higher values lead to fewer
+/// shortcuts, but to a slower preprocessing.
+const WITNESS_SEARCH_LIMIT: usize = 500;
+
+/// A shortcut edge, which replaces a path of two arcs via a contracted node. Arcs are either
+/// edges of the graph (if their index is smaller than the number of edges), or shortcuts
+/// (whose index is offset by the number of edges).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Shortcut {
+    /// Where this shortcut starts.
+    pub source: usize,
+    /// Where this shortcut ends.
+    pub target: usize,
+    /// The weight of this shortcut, i.e., the sum of the weights of the replaced arcs.
+    pub weight: f32,
+    /// The first replaced arc, leading from the source to the contracted node.
+    pub first: usize,
+    /// The second replaced arc, leading from the contracted node to the target.
+    pub second: usize,
+}
+
+/// The result of the contraction hierarchies preprocessing, which can be serialized together
+/// with a graph.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContractionHierarchy {
+    /// The rank of every node, i.e., the position in which it was contracted.
+    pub ranks: Vec<usize>,
+    /// All shortcuts that were added during the contraction.
+    pub shortcuts: Vec<Shortcut>,
+}
+
+/// A contraction hierarchy prepared for queries, holding an upward and a downward index
+/// over all arcs.
+pub struct Hierarchy {
+    /// The rank of every node.
+    pub ranks: Vec<usize>,
+    /// All shortcuts of the hierarchy.
+    pub shortcuts: Vec<Shortcut>,
+    /// The number of edges of the graph, i.e., the index of the first shortcut arc.
+    edges_count: usize,
+    /// Source, target and weight of every arc.
+    arcs: Vec<(usize, usize, f32)>,
+    /// Offsets into `up_arcs`, in compressed sparse row format.
+    up_offsets: Vec<usize>,
+    /// The arcs leading to a node of higher rank, ordered by their source.
+    up_arcs: Vec<usize>,
+    /// Offsets into `down_arcs`, in compressed sparse row format.
+    down_offsets: Vec<usize>,
+    /// The arcs coming from a node of higher rank, ordered by their target.
+    down_arcs: Vec<usize>,
+}
+
+/// Holds the state of the graph while it is being contracted.
+struct Contractor {
+    /// The outgoing arcs (target, weight, arc) of every node that is not yet contracted.
+    out_arcs: Vec<Vec<(usize, f32, usize)>>,
+    /// The incoming arcs (source, weight, arc) of every node that is not yet contracted.
+    in_arcs: Vec<Vec<(usize, f32, usize)>>,
+    /// The number of contracted neighbours of every node, which is used to contract
+    /// the graph uniformly.
+    contracted_neighbours: Vec<usize>,
+    /// All shortcuts added so far.
+    shortcuts: Vec<Shortcut>,
+    /// The number of edges of the graph.
+    edges_count: usize,
+    /// Distances of the witness search, which are reset after every search.
+    dist: Vec<f32>,
+    /// The nodes whose witness search distance was set.
+    touched: Vec<usize>,
+}
+
+impl ContractionHierarchy {
+    /// Contracts all nodes of a graph, one by one, in the order given by their edge difference
+    /// (the number of added shortcuts minus the number of removed edges), using the edge weights
+    /// of a weighting. Returns `None` if the weighting has negative edge weights, as these
+    /// cannot be contracted.
+    pub fn build(nodes_count: usize, edges: &Vec<Edge>, weighting: Weighting)
+                 -> Option<ContractionHierarchy> {
+        if edges.iter().any(|e| e.weight(weighting).map_or(false, |w| w < 0.0)) {
+            return None;
+        }
+
+        let mut contractor = Contractor::new(nodes_count, edges, weighting);
+        let mut queue = BinaryHeap::new();
+        for node in 0..nodes_count {
+            queue.push(Reverse((contractor.priority(node), node)));
+        }
+
+        let mut pb = ProgressBar::new(nodes_count as u64);
+        pb.format("╢▌▌░╟");
+        let mut ranks = vec![0; nodes_count];
+        let mut rank = 0;
+        while let Some(Reverse((_, node))) = queue.pop() {
+            // The priorities are updated lazily: if the node became less attractive since it
+            // was inserted, it is put back into the queue.
+            let priority = contractor.priority(node);
+            if let Some(&Reverse((next_priority, _))) = queue.peek() {
+                if priority > next_priority {
+                    queue.push(Reverse((priority, node)));
+                    continue;
+                }
+            }
+
+            contractor.contract(node);
+            ranks[node] = rank;
+            rank += 1;
+            pb.inc();
+        }
+
+        Some(ContractionHierarchy { ranks: ranks, shortcuts: contractor.shortcuts })
+    }
+}
+
+impl Contractor {
+    /// Creates a new contractor, holding all edges of the graph that can be used by the
+    /// transport mode of the weighting as arcs.
+    fn new(nodes_count: usize, edges: &Vec<Edge>, weighting: Weighting) -> Contractor {
+        let mut out_arcs = vec![Vec::new(); nodes_count];
+        let mut in_arcs = vec![Vec::new(); nodes_count];
+        for (e, edge) in edges.iter().enumerate() {
+            let source = edge.source as usize;
+            let target = edge.target as usize;
+            match edge.weight(weighting) {
+                Some(weight) if source != target => {
+                    out_arcs[source].push((target, weight, e));
+                    in_arcs[target].push((source, weight, e));
+                },
+                _ => {}
+            }
+        }
+
+        Contractor {
+            out_arcs: out_arcs,
+            in_arcs: in_arcs,
+            contracted_neighbours: vec![0; nodes_count],
+            shortcuts: Vec::new(),
+            edges_count: edges.len(),
+            dist: vec![std::f32::MAX; nodes_count],
+            touched: Vec::new(),
+        }
+    }
+
+    /// The priority of a node; nodes with a lower priority are contracted first.
+    fn priority(&mut self, node: usize) -> i64 {
+        let shortcuts = self.needed_shortcuts(node).len() as i64;
+        let removed = (self.in_arcs[node].len() + self.out_arcs[node].len()) as i64;
+        shortcuts - removed + self.contracted_neighbours[node] as i64
+    }
+
+    /// Contracts a node, adding all required shortcuts and removing it from its neighbours.
+    fn contract(&mut self, node: usize) {
+        for shortcut in self.needed_shortcuts(node) {
+            let arc = self.edges_count + self.shortcuts.len();
+            self.out_arcs[shortcut.source].push((shortcut.target, shortcut.weight, arc));
+            self.in_arcs[shortcut.target].push((shortcut.source, shortcut.weight, arc));
+            self.shortcuts.push(shortcut);
+        }
+
+        let in_arcs = std::mem::replace(&mut self.in_arcs[node], Vec::new());
+        let out_arcs = std::mem::replace(&mut self.out_arcs[node], Vec::new());
+        for &(source, _, _) in &in_arcs {
+            self.out_arcs[source].retain(|a| a.0 != node);
+            self.contracted_neighbours[source] += 1;
+        }
+        for &(target, _, _) in &out_arcs {
+            self.in_arcs[target].retain(|a| a.0 != node);
+            self.contracted_neighbours[target] += 1;
+        }
+    }
+
+    /// Computes the shortcuts that are needed when contracting a node. A shortcut from u to w
+    /// is needed if the path u -> node -> w is the only shortest path between them, i.e., if
+    /// no witness path avoiding the node is found.
+    fn needed_shortcuts(&mut self, node: usize) -> Vec<Shortcut> {
+        let in_arcs = cheapest_arcs(&self.in_arcs[node]);
+        let out_arcs = cheapest_arcs(&self.out_arcs[node]);
+        let mut shortcuts = Vec::new();
+
+        for &(source, in_weight, in_arc) in &in_arcs {
+            let max_out_weight = out_arcs.iter()
+                .filter(|a| a.0 != source)
+                .map(|a| a.1)
+                .fold(std::f32::MIN, f32::max);
+            if max_out_weight == std::f32::MIN {
+                continue;
+            }
+
+            self.witness_search(source, node, in_weight + max_out_weight);
+            for &(target, out_weight, out_arc) in &out_arcs {
+                if target != source && self.dist[target] > in_weight + out_weight {
+                    shortcuts.push(Shortcut {
+                        source: source,
+                        target: target,
+                        weight: in_weight + out_weight,
+                        first: in_arc,
+                        second: out_arc,
+                    });
+                }
+            }
+        }
+
+        shortcuts
+    }
+
+    /// Runs a Djikstra search from the source that avoids the given node, and stops as soon as
+    /// the distances exceed `max_cost` or enough nodes were settled.
+    fn witness_search(&mut self, source: usize, avoid: usize, max_cost: f32) {
+        for &n in &self.touched {
+            self.dist[n] = std::f32::MAX;
+        }
+        self.touched.clear();
+
+        let mut heap = BinaryHeap::new();
+        self.dist[source] = 0.0;
+        self.touched.push(source);
+        heap.push(State { cost: 0.0, position: source });
+
+        let mut settled = 0;
+        while let Some(State { cost, position }) = heap.pop() {
+            if cost > self.dist[position] { continue; }
+            if cost > max_cost || settled >= WITNESS_SEARCH_LIMIT { break; }
+            settled += 1;
+
+            for &(next, weight, _) in &self.out_arcs[position] {
+                if next == avoid { continue; }
+                if cost + weight < self.dist[next] {
+                    if self.dist[next] == std::f32::MAX {
+                        self.touched.push(next);
+                    }
+                    self.dist[next] = cost + weight;
+                    heap.push(State { cost: cost + weight, position: next });
+                }
+            }
+        }
+    }
+}
+
+/// Reduces a list of arcs to the cheapest one per neighbour.
+fn cheapest_arcs(arcs: &Vec<(usize, f32, usize)>) -> Vec<(usize, f32, usize)> {
+    let mut cheapest: Vec<(usize, f32, usize)> = Vec::with_capacity(arcs.len());
+    for &arc in arcs {
+        match cheapest.iter().position(|a| a.0 == arc.0) {
+            Some(i) => if arc.1 < cheapest[i].1 { cheapest[i] = arc; },
+            None => cheapest.push(arc)
+        }
+    }
+    cheapest
+}
+
+impl Hierarchy {
+    /// Prepares a contraction hierarchy of the given edges for queries, using the edge weights
+    /// of the weighting it was built for. Edges the transport mode cannot use are left out
+    /// of the upward and downward index.
+    pub fn new(ch: &ContractionHierarchy, edges: &Vec<Edge>, weighting: Weighting) -> Hierarchy {
+        let nodes_count = ch.ranks.len();
+        let valid = |a: usize| a >= edges.len() || edges[a].weight(weighting).is_some();
+        let arcs = edges.iter()
+            .map(|e| (e.source as usize, e.target as usize, e.weight(weighting).unwrap_or(std::f32::MAX)))
+            .chain(ch.shortcuts.iter().map(|s| (s.source, s.target, s.weight)))
+            .collect::<Vec<_>>();
+
+        let up = (0..arcs.len())
+            .filter(|&a| valid(a) && ch.ranks[arcs[a].0] < ch.ranks[arcs[a].1])
+            .collect::<Vec<_>>();
+        let (up_offsets, up_index) = build_adjacency(nodes_count, up.iter().map(|&a| arcs[a].0));
+        let down = (0..arcs.len())
+            .filter(|&a| valid(a) && ch.ranks[arcs[a].0] > ch.ranks[arcs[a].1])
+            .collect::<Vec<_>>();
+        let (down_offsets, down_index) = build_adjacency(nodes_count, down.iter().map(|&a| arcs[a].1));
+
+        Hierarchy {
+            ranks: ch.ranks.clone(),
+            shortcuts: ch.shortcuts.clone(),
+            edges_count: edges.len(),
+            up_offsets: up_offsets,
+            up_arcs: up_index.iter().map(|&i| up[i]).collect(),
+            down_offsets: down_offsets,
+            down_arcs: down_index.iter().map(|&i| down[i]).collect(),
+            arcs: arcs,
+        }
+    }
+
+    /// Runs a bidirectional Djikstra search on the hierarchy, where the forward search only
+    /// follows arcs upwards from the sources, and the backward search only follows arcs
+    /// upwards from the targets. Sources and targets are given with the cost of reaching them
+    /// (or the target from them). Returns the first node of the shortest path, the cost of
+    /// reaching it, and the edges of the path with all shortcuts unpacked, or `None` if no
+    /// target can be reached.
+    pub fn query(&self, sources: &[(usize, f32)], targets: &[(usize, f32)])
+                 -> Option<(usize, f32, Vec<usize>)> {
+        let nodes_count = self.ranks.len();
+        let mut dist_fwd = vec![std::f32::MAX; nodes_count];
+        let mut dist_bwd = vec![std::f32::MAX; nodes_count];
+        let mut pred = vec![None; nodes_count];
+        let mut succ = vec![None; nodes_count];
+        let mut heap_fwd = BinaryHeap::new();
+        let mut heap_bwd = BinaryHeap::new();
+
+        for &(source, cost) in sources {
+            if cost < dist_fwd[source] {
+                dist_fwd[source] = cost;
+                heap_fwd.push(State { cost: cost, position: source });
+            }
+        }
+        for &(target, cost) in targets {
+            if cost < dist_bwd[target] {
+                dist_bwd[target] = cost;
+                heap_bwd.push(State { cost: cost, position: target });
+            }
+        }
+
+        let mut best = std::f32::MAX;
+        let mut meeting = None;
+        for &(source, _) in sources {
+            if dist_bwd[source] != std::f32::MAX && dist_fwd[source] + dist_bwd[source] < best {
+                best = dist_fwd[source] + dist_bwd[source];
+                meeting = Some(source);
+            }
+        }
+
+        // In contrast to a plain bidirectional search, both searches have to continue until
+        // they cannot improve the best path anymore, as they do not meet at the first node
+        // settled by both of them.
+        loop {
+            let top_fwd = heap_fwd.peek().map(|s| s.cost).unwrap_or(std::f32::MAX);
+            let top_bwd = heap_bwd.peek().map(|s| s.cost).unwrap_or(std::f32::MAX);
+            let fwd_done = top_fwd >= best;
+            let bwd_done = top_bwd >= best;
+            if fwd_done && bwd_done {
+                break;
+            }
+
+            if !fwd_done && (bwd_done || top_fwd <= top_bwd) {
+                let State { cost, position } = heap_fwd.pop().unwrap();
+                if cost > dist_fwd[position] { continue; }
+
+                for &a in &self.up_arcs[self.up_offsets[position]..self.up_offsets[position + 1]] {
+                    let (_, next, weight) = self.arcs[a];
+                    if cost + weight < dist_fwd[next] {
+                        dist_fwd[next] = cost + weight;
+                        pred[next] = Some(a);
+                        heap_fwd.push(State { cost: dist_fwd[next], position: next });
+                    }
+                    if dist_bwd[next] != std::f32::MAX && dist_fwd[next] + dist_bwd[next] < best {
+                        best = dist_fwd[next] + dist_bwd[next];
+                        meeting = Some(next);
+                    }
+                }
+            } else {
+                let State { cost, position } = heap_bwd.pop().unwrap();
+                if cost > dist_bwd[position] { continue; }
+
+                for &a in &self.down_arcs[self.down_offsets[position]..self.down_offsets[position + 1]] {
+                    let (next, _, weight) = self.arcs[a];
+                    if cost + weight < dist_bwd[next] {
+                        dist_bwd[next] = cost + weight;
+                        succ[next] = Some(a);
+                        heap_bwd.push(State { cost: dist_bwd[next], position: next });
+                    }
+                    if dist_fwd[next] != std::f32::MAX && dist_fwd[next] + dist_bwd[next] < best {
+                        best = dist_fwd[next] + dist_bwd[next];
+                        meeting = Some(next);
+                    }
+                }
+            }
+        }
+
+        let meeting = match meeting {
+            Some(meeting) => meeting,
+            None => return None
+        };
+
+        // Collect the arcs from the source to the meeting node, and from there to the target.
+        let mut arcs = Vec::new();
+        let mut current_node = meeting;
+        while let Some(a) = pred[current_node] {
+            arcs.push(a);
+            current_node = self.arcs[a].0;
+        }
+        let start = current_node;
+        arcs.reverse();
+        let mut current_node = meeting;
+        while let Some(a) = succ[current_node] {
+            arcs.push(a);
+            current_node = self.arcs[a].1;
+        }
+
+        let mut edges = Vec::new();
+        for a in arcs {
+            self.unpack(a, &mut edges);
+        }
+        Some((start, dist_fwd[start], edges))
+    }
+
+    /// Recursively replaces an arc by the edges it consists of.
+    fn unpack(&self, arc: usize, edges: &mut Vec<usize>) {
+        let mut stack = vec![arc];
+        while let Some(a) = stack.pop() {
+            if a < self.edges_count {
+                edges.push(a);
+            } else {
+                let shortcut = &self.shortcuts[a - self.edges_count];
+                stack.push(shortcut.second);
+                stack.push(shortcut.first);
+            }
+        }
+    }
+}
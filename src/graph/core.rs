@@ -1,8 +1,10 @@
 use std;
 use std::fs::File;
 use std::io::{BufReader, Seek, SeekFrom};
+use std::fmt;
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use std::ops::Add;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use byteorder::{LittleEndian, ReadBytesExt};
 use pbr::ProgressBar;
 use spade::rtree::RTree;
@@ -10,10 +12,19 @@ use cgmath::Point2;
 use postgres::{Connection, TlsMode};
 
 use spatialpoint::SpatialPoint;
+use spatialedge::SpatialEdge;
+use graph::contraction::Hierarchy;
+use graph::charging::{ChargingStation, ChargingNetwork};
+use graph::vehicle::VehicleProfile;
+use graph::restriction::RestrictionTrie;
+
+/// The maximal number of labels created by a Pareto search, which keeps it from running
+/// forever (e.g., around cycles recuperating energy) if the target cannot be reached.
+const PARETO_MAX_LABELS: usize = 10_000_000;
 
 // Inspired by http://codegists.com/snippet/rust/bellmanrs_tristramg_rust.
 
-/// Holds a single node, containing the OSM id, longitude, and latitude.
+/// Holds a single node, containing the OSM id, longitude, latitude, and elevation.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Node {
     /// The OSM id associated with this node.
@@ -22,22 +33,84 @@ pub struct Node {
     pub lon: f64,
     /// The latitude of this node.
     pub lat: f64,
+    /// The elevation of this node in meters, as sampled from the DEM.
+    pub elevation: f64,
+}
+
+/// The costs of traversing an edge (or a path), in terms of every metric.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Costs {
+    /// The distance in kilometers.
+    pub distance: f32,
+    /// The duration in seconds.
+    pub duration: f32,
+    /// The energy in kWh, which is negative if energy is recuperated.
+    pub energy: f32,
 }
 
 /// Holds a single edge, containing the source node, the target node,
-/// and the edge weight.
+/// and the edge costs for every transport mode.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Edge {
     /// Where this edge starts.
     pub source: i64,
     /// Where this edge ends.
     pub target: i64,
-    /// The weight of this edge.
-    pub weight: f32,
+    /// The costs of this edge for every transport mode of the graph, or `None` if the
+    /// edge cannot be used by a transport mode.
+    pub costs: Vec<Option<Costs>>,
+    /// The length of this edge in kilometers.
+    pub length: f32,
+    /// The slope of this edge, i.e., the elevation difference divided by the length.
+    pub slope: f32,
+    /// The longitudes and latitudes of the points between source and target describing the
+    /// shape of this edge, from source to target.
+    pub geometry: Vec<(f64, f64)>,
+    /// The OSM id of the way this edge was created from, or 0 if it is unknown.
+    pub way: i64,
     /// The tag of this edge.
     pub highway_tag: String,
 }
 
+/// The costs of turning from one edge onto another, as computed by the transport mode scripts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TurnCost {
+    /// The edge the turn starts from.
+    pub from: usize,
+    /// The edge the turn leads onto.
+    pub to: usize,
+    /// The costs of the turn for every transport mode of the graph, or `None` if a transport
+    /// mode has no costs for it.
+    pub costs: Vec<Option<Costs>>,
+}
+
+/// A path through the graph, as found by one of the routing algorithms.
+#[derive(Debug, Clone)]
+pub struct Path {
+    /// The internal ids of all nodes along the path, from source to target.
+    pub nodes: Vec<usize>,
+    /// The indices of all edges along the path, from source to target.
+    pub edges: Vec<usize>,
+    /// The cumulative cost at every node along the path, starting with the cost of reaching
+    /// the first node (usually 0).
+    pub costs: Vec<f32>,
+}
+
+/// A temporary node, which is inserted into the graph for a single query. It is either
+/// located at a node of the graph, or at a point projected onto an edge, in which case it
+/// is linked to the nodes of that edge by partial edges.
+#[derive(Debug, Clone)]
+pub struct VirtualNode {
+    /// The location of this node. Its OSM id is -1 if it does not correspond to a node.
+    pub node: Node,
+    /// The edge and the fraction of it (from its source) at which this node lies, if any.
+    pub edge: Option<(usize, f64)>,
+    /// The nodes that can be reached from this node, with the costs of the partial edges.
+    pub outgoing: Vec<(usize, Costs)>,
+    /// The nodes from which this node can be reached, with the costs of the partial edges.
+    pub incoming: Vec<(usize, Costs)>,
+}
+
 /// Contains a whole graph.
 pub struct Graph {
     /// All the edges contained in the graph.
@@ -46,6 +119,43 @@ pub struct Graph {
     pub nodes: Vec<Node>,
     /// An R tree for quick access to the nodes, given a longitude and latitude.
     pub rtree: RTree<SpatialPoint>,
+    /// An R tree for quick access to the edges, given a longitude and latitude.
+    pub edge_rtree: RTree<SpatialEdge>,
+    /// The names of the transport modes of this graph, in the order of the edge costs.
+    pub modes: Vec<String>,
+    /// Maps the OSM ids of all nodes to their internal ids.
+    pub osm_index: HashMap<i64, usize>,
+    /// Offsets into `out_edges`, in compressed sparse row format. The outgoing edges of node
+    /// `i` are `out_edges[out_offsets[i]..out_offsets[i + 1]]`.
+    pub out_offsets: Vec<usize>,
+    /// The indices of all edges, ordered by their source node.
+    pub out_edges: Vec<usize>,
+    /// Offsets into `in_edges`, in compressed sparse row format. The incoming edges of node
+    /// `i` are `in_edges[in_offsets[i]..in_offsets[i + 1]]`.
+    pub in_offsets: Vec<usize>,
+    /// The indices of all edges, ordered by their target node.
+    pub in_edges: Vec<usize>,
+    /// The minimal edge costs per kilometer found in the graph (at least 0) for every
+    /// transport mode, which makes the Haversine distance an admissible heuristic for A*.
+    pub min_cost_per_km: Vec<Costs>,
+    /// The minimal edge costs found in the graph for every transport mode, which tell whether a
+    /// metric has negative edge weights without looking at every edge.
+    pub min_costs: Vec<Costs>,
+    /// The minimal slope of the edges of every transport mode (at most 0), from which the minimal
+    /// energy of a vehicle profile per kilometer is derived.
+    pub min_slope: Vec<f32>,
+    /// The contraction hierarchies of all weightings that were preprocessed.
+    pub hierarchies: Vec<(Weighting, Hierarchy)>,
+    /// All charging stations for electric vehicles.
+    pub charging_stations: Vec<ChargingStation>,
+    /// The charging stations linked to the graph and the legs between them for every transport
+    /// mode, once `link_charging_stations` was called.
+    pub charging_networks: Vec<ChargingNetwork>,
+    /// The turn restrictions, which are honoured by the turn-aware search.
+    pub turn_restrictions: RestrictionTrie,
+    /// The costs of all turns from one edge onto another (given by their indices) for every
+    /// transport mode, if the turn has costs. They are applied by the turn-aware search.
+    pub turn_costs: HashMap<(usize, usize), Vec<Option<Costs>>>,
 }
 
 /// Implementation of node.
@@ -61,6 +171,7 @@ impl Node {
             id: id,
             lon: lon as f64 / 1e6,
             lat: lat as f64 / 1e6,
+            elevation: 0.0,
         }
     }
 
@@ -79,8 +190,66 @@ impl Node {
     }
 }
 
+/// Implementation of costs.
+impl Costs {
+    /// Costs of 0 in terms of every metric.
+    pub fn zero() -> Costs {
+        Costs { distance: 0.0, duration: 0.0, energy: 0.0 }
+    }
+
+    /// Costs having the same value for every metric, for graphs that only know a single weight.
+    pub fn uniform(weight: f32) -> Costs {
+        Costs { distance: weight, duration: weight, energy: weight }
+    }
+
+    /// Gets the cost in terms of a metric.
+    pub fn get(&self, metric: Metric) -> f32 {
+        match metric {
+            Metric::Distance => self.distance,
+            Metric::Duration => self.duration,
+            Metric::Energy => self.energy,
+        }
+    }
+
+    /// Scales the costs by a factor, e.g., for the fraction of an edge.
+    pub fn scale(&self, factor: f64) -> Costs {
+        Costs {
+            distance: (self.distance as f64 * factor) as f32,
+            duration: (self.duration as f64 * factor) as f32,
+            energy: (self.energy as f64 * factor) as f32,
+        }
+    }
+}
+
+impl Add for Costs {
+    type Output = Costs;
+
+    fn add(self, other: Costs) -> Costs {
+        Costs {
+            distance: self.distance + other.distance,
+            duration: self.duration + other.duration,
+            energy: self.energy + other.energy,
+        }
+    }
+}
+
 /// Implementation of edge.
 impl Edge {
+    /// The costs of this edge for the transport mode of a weighting, or `None` if the transport
+    /// mode cannot use it. If the weighting has a vehicle profile, the energy is computed from
+    /// the profile, using the length, the slope, and the duration of the transport mode.
+    pub fn costs_for(&self, weighting: Weighting) -> Option<Costs> {
+        self.costs[weighting.mode].map(|c| match weighting.vehicle {
+            Some(vehicle) => Costs { energy: vehicle.energy(self.length, self.slope, c.duration), ..c },
+            None => c
+        })
+    }
+
+    /// The weight of this edge for a weighting, or `None` if the transport mode cannot use it.
+    pub fn weight(&self, weighting: Weighting) -> Option<f32> {
+        self.costs_for(weighting).map(|c| c.get(weighting.metric))
+    }
+
     /// Reads an edge from an OSRM file.
     fn from_osrm(reader: &mut BufReader<&File>) -> Edge {
         let source = reader.read_u32::<LittleEndian>().unwrap() as i64;
@@ -92,12 +261,24 @@ impl Edge {
         Edge {
             source: source,
             target: target,
-            weight: weight as f32,
+            costs: vec![Some(Costs::uniform(weight as f32))],
+            length: 0.0,
+            slope: 0.0,
+            geometry: Vec::new(),
+            way: 0,
             highway_tag: "".to_string(),
         }
     }
 }
 
+/// Implementation of path.
+impl Path {
+    /// The total cost of this path.
+    pub fn cost(&self) -> f32 {
+        *self.costs.last().unwrap_or(&0.0)
+    }
+}
+
 /// Implementation of graph.
 impl Graph {
     /// Creates a new graph, by reading an OSRM file. This also adds and returns an OSM id
@@ -135,12 +316,12 @@ impl Graph {
 
         // Finally, we build an R tree for quick access.
         let mut rtree = RTree::new();
-        for n in nodes.iter() {
-            let p = SpatialPoint::new(Point2::new(n.lon, n.lat), n.id);
+        for (i, n) in nodes.iter().enumerate() {
+            let p = SpatialPoint::new(Point2::new(n.lon, n.lat), i);
             rtree.insert(p);
         }
 
-        Graph { edges: edges, nodes: nodes, rtree: rtree }
+        Graph::new(nodes, edges, rtree, vec![String::from("default")])
     }
 
     /// Loads a graph from a Postgres database.
@@ -161,6 +342,7 @@ impl Graph {
                 id: osm_id,
                 lon: lon_raw,
                 lat: lat_raw,
+                elevation: 0.0,
             };
             nodes.push(node);
         }
@@ -179,7 +361,11 @@ impl Graph {
             let edge = Edge {
                 source: source_id - 1,
                 target: target_id - 1,
-                weight: weight_raw as f32,
+                costs: vec![Some(Costs::uniform(weight_raw as f32))],
+                length: 0.0,
+                slope: 0.0,
+                geometry: Vec::new(),
+                way: 0,
                 highway_tag: "".to_string(),
             };
             edges.push(edge);
@@ -188,7 +374,11 @@ impl Graph {
             let edge = Edge {
                 source: target_id - 1,
                 target: source_id - 1,
-                weight: weight_raw_rev as f32,
+                costs: vec![Some(Costs::uniform(weight_raw_rev as f32))],
+                length: 0.0,
+                slope: 0.0,
+                geometry: Vec::new(),
+                way: 0,
                 highway_tag: "".to_string(),
             };
             edges.push(edge);
@@ -196,189 +386,1543 @@ impl Graph {
 
         // Finally, we build an R tree for quick access.
         let mut rtree = RTree::new();
-        for n in nodes.iter() {
-            let p = SpatialPoint::new(Point2::new(n.lon, n.lat), n.id);
+        for (i, n) in nodes.iter().enumerate() {
+            let p = SpatialPoint::new(Point2::new(n.lon, n.lat), i);
             rtree.insert(p);
         }
 
-        Graph { edges: edges, nodes: nodes, rtree: rtree }
+        Graph::new(nodes, edges, rtree, vec![String::from("default")])
+    }
+
+    /// Creates a new graph from its nodes, edges (having costs per transport mode) and
+    /// R tree, and builds the forward and backward adjacency index, as well as an R tree of
+    /// the edges, containing every segment of their geometry.
+    pub fn new(nodes: Vec<Node>, edges: Vec<Edge>, rtree: RTree<SpatialPoint>,
+               modes: Vec<String>) -> Graph {
+        let mut edge_rtree = RTree::new();
+        for (i, e) in edges.iter().enumerate() {
+            let points = polyline(&nodes, e);
+            let fractions = polyline_fractions(&points);
+            for j in 1..points.len() {
+                edge_rtree.insert(SpatialEdge::new(Point2::new(points[j - 1].0, points[j - 1].1),
+                                                   Point2::new(points[j].0, points[j].1),
+                                                   i, fractions[j - 1], fractions[j]));
+            }
+        }
+        let (out_offsets, out_edges) = build_adjacency(
+            nodes.len(), edges.iter().map(|e| e.source as usize));
+        let (in_offsets, in_edges) = build_adjacency(
+            nodes.len(), edges.iter().map(|e| e.target as usize));
+        let min_cost_per_km = (0..modes.len())
+            .map(|mode| min_cost_per_km(&nodes, &edges, mode))
+            .collect();
+        let min_costs = (0..modes.len())
+            .map(|mode| min_costs(&edges, mode))
+            .collect();
+        let min_slope = (0..modes.len())
+            .map(|mode| edges.iter().filter(|e| e.costs[mode].is_some())
+                 .fold(0.0, |min: f32, e| min.min(e.slope)))
+            .collect();
+        let osm_index = nodes.iter().enumerate().map(|(i, n)| (n.id, i)).collect();
+
+        Graph {
+            edges: edges,
+            nodes: nodes,
+            rtree: rtree,
+            edge_rtree: edge_rtree,
+            modes: modes.clone(),
+            osm_index: osm_index,
+            out_offsets: out_offsets,
+            out_edges: out_edges,
+            in_offsets: in_offsets,
+            in_edges: in_edges,
+            min_cost_per_km: min_cost_per_km,
+            min_costs: min_costs,
+            min_slope: min_slope,
+            hierarchies: Vec::new(),
+            charging_stations: Vec::new(),
+            charging_networks: Vec::new(),
+            turn_restrictions: RestrictionTrie::new(&[]),
+            turn_costs: HashMap::new(),
+        }
+    }
+
+    /// Gets the index of a transport mode from its name.
+    pub fn mode_index(&self, name: &str) -> Option<usize> {
+        self.modes.iter().position(|m| m == name)
+    }
+
+    /// Returns the costs of an edge for a transport mode, or `None` if the transport mode
+    /// cannot use the edge.
+    pub fn costs(&self, edge: usize, mode: usize) -> Option<Costs> {
+        self.edges[edge].costs[mode]
+    }
+
+    /// Returns the costs of an edge for a weighting, where the energy is computed from the vehicle
+    /// profile of the weighting, if any, or `None` if the transport mode cannot use the edge.
+    pub fn costs_for(&self, edge: usize, weighting: Weighting) -> Option<Costs> {
+        self.edges[edge].costs_for(weighting)
+    }
+
+    /// Returns the weight of an edge for a weighting, i.e., its cost in terms of the metric
+    /// for the transport mode, or `None` if the transport mode cannot use the edge.
+    pub fn weight(&self, edge: usize, weighting: Weighting) -> Option<f32> {
+        self.edges[edge].weight(weighting)
+    }
+
+    /// Returns the minimal edge weight of a weighting per kilometer of Haversine distance, which
+    /// makes the Haversine distance an admissible heuristic for A* if no edge weight is negative.
+    /// It is preprocessed, except for the energy of a vehicle profile, which is derived from the
+    /// profile, the minimal slope and the minimal duration per kilometer instead.
+    pub fn lower_bound_per_km(&self, weighting: Weighting) -> f32 {
+        let min_cost_per_km = self.min_cost_per_km[weighting.mode];
+        match (weighting.vehicle, weighting.metric) {
+            (Some(vehicle), Metric::Energy) =>
+                vehicle.min_energy_per_km(self.min_slope[weighting.mode], min_cost_per_km.duration),
+            _ => min_cost_per_km.get(weighting.metric)
+        }
+    }
+
+    /// Returns the contraction hierarchy of a weighting, if it was preprocessed. Hierarchies are
+    /// preprocessed without vehicle profile, which only changes the energy of the edges, so there
+    /// is never a hierarchy for the energy of a vehicle profile.
+    pub fn hierarchy(&self, weighting: Weighting) -> Option<&Hierarchy> {
+        let weighting = match weighting.metric {
+            Metric::Energy => weighting,
+            _ => Weighting { vehicle: None, ..weighting }
+        };
+        self.hierarchies.iter().find(|h| h.0 == weighting).map(|h| &h.1)
+    }
+
+    /// Checks whether a node can be used by a transport mode, i.e., whether any of its
+    /// edges can.
+    fn node_valid(&self, node: usize, mode: usize) -> bool {
+        self.outgoing(node).iter().chain(self.incoming(node).iter())
+            .any(|&e| self.costs(e, mode).is_some())
+    }
+
+    /// Returns the indices of all edges starting at the given node.
+    pub fn outgoing(&self, node: usize) -> &[usize] {
+        &self.out_edges[self.out_offsets[node]..self.out_offsets[node + 1]]
+    }
+
+    /// Returns the indices of all edges ending at the given node.
+    pub fn incoming(&self, node: usize) -> &[usize] {
+        &self.in_edges[self.in_offsets[node]..self.in_offsets[node + 1]]
+    }
+
+    /// Creates a virtual node at the location of a node of the graph.
+    pub fn virtual_node(&self, id: usize) -> VirtualNode {
+        VirtualNode {
+            node: self.nodes[id].clone(),
+            edge: None,
+            outgoing: vec![(id, Costs::zero())],
+            incoming: vec![(id, Costs::zero())],
+        }
+    }
+
+    /// Projects a longitude and latitude onto the closest edge (following its geometry), and
+    /// creates a virtual node at the projected point. The virtual node is linked to the nodes of
+    /// the edge (and of the edge in the opposite direction, if there is one), where the costs of
+    /// the partial edges are proportional to the fraction of the edge they cover. Only edges that
+    /// can be used by the transport mode of the weighting are considered. If a maximal distance (in
+    /// kilometers) is given, edges further away than that are not considered either.
+    pub fn snap_to_edge(&self, lon: f64, lat: f64, max_distance: Option<f64>, weighting: Weighting)
+                        -> Result<VirtualNode, RoutingError> {
+        let mode = weighting.mode;
+        let point = Point2::new(lon, lat);
+        let no_edge = RoutingError::NoNodeNearby(lon, lat, max_distance.unwrap_or(0.0));
+        let nearest = self.edge_rtree.nearest_neighbor_iterator(&point)
+            .find(|e| self.costs(e.index, mode).is_some());
+        let nearest = match nearest {
+            Some(nearest) => nearest,
+            None => return Err(no_edge)
+        };
+
+        let (fraction, projected) = nearest.project(&point);
+        let edge = &self.edges[nearest.index];
+        let source = &self.nodes[edge.source as usize];
+        let target = &self.nodes[edge.target as usize];
+        let node = Node {
+            id: -1,
+            lon: projected.x,
+            lat: projected.y,
+            elevation: source.elevation + fraction * (target.elevation - source.elevation),
+        };
+        if let Some(max_distance) = max_distance {
+            if self.dist_to_node_at(&node, lon, lat) > max_distance {
+                return Err(no_edge);
+            }
+        }
+
+        let mut outgoing = Vec::new();
+        let mut incoming = Vec::new();
+        for (e, f) in self.parallel_edges(nearest.index, fraction, mode) {
+            let edge = &self.edges[e];
+            let costs = self.costs_for(e, weighting).unwrap();
+            outgoing.push((edge.target as usize, costs.scale(1.0 - f)));
+            incoming.push((edge.source as usize, costs.scale(f)));
+        }
+
+        Ok(VirtualNode {
+            node: node,
+            edge: Some((nearest.index, fraction)),
+            outgoing: outgoing,
+            incoming: incoming,
+        })
     }
 
-    /// Gets the node IDs from a longitude and latitude.
-    pub fn get_id_from_lon_lat(&self, lon: f64, lat: f64) -> i64 {
-        let nearest = self.rtree.nearest_neighbor(&Point2::new(lon, lat)).unwrap();
-        nearest.id
+    /// Returns all edges between the two nodes of an edge (in both directions) that can be used
+    /// by the transport mode, together with the given fraction of the edge, converted to the
+    /// direction of the respective edge.
+    fn parallel_edges(&self, edge: usize, fraction: f64, mode: usize) -> Vec<(usize, f64)> {
+        let source = self.edges[edge].source;
+        let target = self.edges[edge].target;
+        let mut parallel = Vec::new();
+        for &e in self.outgoing(source as usize) {
+            if self.edges[e].target == target && self.costs(e, mode).is_some() {
+                parallel.push((e, fraction));
+            }
+        }
+        for &e in self.outgoing(target as usize) {
+            if self.edges[e].target == source && self.costs(e, mode).is_some() {
+                parallel.push((e, 1.0 - fraction));
+            }
+        }
+        parallel
+    }
+
+    /// Computes the route going directly from one virtual node to another, which is possible
+    /// if both lie on the same edge, and the target lies after the source. If there are several
+    /// such edges, the cheapest one for the weighting is used. Returns the trace and the costs.
+    fn direct_route(&self, source: &VirtualNode, target: &VirtualNode, weighting: Weighting)
+                    -> Option<(Vec<Vec<f64>>, Costs)> {
+        match (source.edge, target.edge) {
+            (Some((source_edge, source_fraction)), Some((target_edge, target_fraction))) => {
+                let target_edges = self.parallel_edges(target_edge, target_fraction, weighting.mode);
+                let loc = |n: &Node| vec![n.lon, n.lat];
+                self.parallel_edges(source_edge, source_fraction, weighting.mode).iter()
+                    .filter_map(|&(e, fs)| target_edges.iter()
+                        .find(|t| t.0 == e && t.1 >= fs)
+                        .map(|&(_, ft)| (e, fs, ft, self.costs_for(e, weighting).unwrap().scale(ft - fs))))
+                    .fold(None, |min: Option<(usize, f64, f64, Costs)>, link| match min {
+                        Some(min) if min.3.get(weighting.metric) <= link.3.get(weighting.metric) => Some(min),
+                        _ => Some(link)
+                    })
+                    .map(|(e, fs, ft, costs)| {
+                        let mut trace = vec![loc(&source.node)];
+                        trace.extend(self.edge_geometry(e, fs, ft));
+                        trace.push(loc(&target.node));
+                        (trace, costs)
+                    })
+            },
+            _ => None
+        }
+    }
+
+    /// Returns the points of the geometry of an edge lying strictly between two fractions of the
+    /// edge (from its source), as longitude and latitude.
+    fn edge_geometry(&self, edge: usize, from: f64, to: f64) -> Vec<Vec<f64>> {
+        let geometry = &self.edges[edge].geometry;
+        if geometry.is_empty() {
+            return Vec::new();
+        }
+        let fractions = polyline_fractions(&polyline(&self.nodes, &self.edges[edge]));
+        geometry.iter().zip(fractions[1..].iter())
+            .filter(|&(_, &f)| f > from && f < to)
+            .map(|(&(lon, lat), _)| vec![lon, lat])
+            .collect()
+    }
+
+    /// Builds the trace of a path from its first node (given as internal id) and the edges along
+    /// it, including the geometry of the edges.
+    pub fn edges_trace(&self, first: usize, edges: &[usize]) -> Vec<Vec<f64>> {
+        let mut trace = vec![self.get_loc_from_id(first)];
+        for &e in edges {
+            trace.extend(self.edge_geometry(e, 0.0, 1.0));
+            trace.push(self.get_loc_from_id(self.edges[e].target as usize));
+        }
+
+        trace
+    }
+
+    /// Builds the trace of a route from a source to a target, which leaves the source towards the
+    /// first node (given as internal id) and then follows the edges. This includes the partial
+    /// edges from the source and to the target, following the geometry of their edges.
+    fn route_trace(&self, source: &VirtualNode, target: &VirtualNode, first: usize, edges: &[usize],
+                   mode: usize) -> Vec<Vec<f64>> {
+        let loc = |n: &Node| vec![n.lon, n.lat];
+        let mut trace = Vec::new();
+        if source.edge.is_some() {
+            trace.push(loc(&source.node));
+            if let Some((e, f)) = self.link_edge(source, first, true, mode) {
+                trace.extend(self.edge_geometry(e, f, 1.0));
+            }
+        }
+        trace.extend(self.edges_trace(first, edges));
+        if target.edge.is_some() {
+            let last = edges.last().map_or(first, |&e| self.edges[e].target as usize);
+            if let Some((e, f)) = self.link_edge(target, last, false, mode) {
+                trace.extend(self.edge_geometry(e, 0.0, f));
+            }
+            trace.push(loc(&target.node));
+        }
+
+        trace
+    }
+
+    /// Finds the partial edge linking a virtual node to a node of the graph, which leaves the
+    /// virtual node if `outgoing` is true, and enters it otherwise. Returns the edge the partial
+    /// edge is part of and the fraction of it at which the virtual node lies, or `None` if the
+    /// virtual node is not located on an edge.
+    fn link_edge(&self, node: &VirtualNode, graph_node: usize, outgoing: bool, mode: usize) -> Option<(usize, f64)> {
+        node.edge.and_then(|(edge, fraction)| self.parallel_edges(edge, fraction, mode).into_iter()
+            .find(|&(e, _)| if outgoing {
+                self.edges[e].target as usize == graph_node
+            } else {
+                self.edges[e].source as usize == graph_node
+            }))
+    }
+
+    /// Returns the costs of turning from one edge onto another for a transport mode, which are
+    /// zero unless its transport mode script defines turn costs.
+    pub fn turn_cost(&self, from: usize, to: usize, mode: usize) -> Costs {
+        self.turn_costs.get(&(from, to)).and_then(|costs| costs[mode]).unwrap_or(Costs::zero())
+    }
+
+    /// Sums up the turn costs along a path, including the turns from the partial edge leaving the
+    /// source and onto the partial edge reaching the target.
+    fn path_turn_costs(&self, source: &VirtualNode, target: &VirtualNode, path: &Path, mode: usize) -> Costs {
+        let mut edges = Vec::new();
+        edges.extend(self.link_edge(source, path.nodes[0], true, mode).map(|link| link.0));
+        edges.extend(path.edges.iter().cloned());
+        edges.extend(self.link_edge(target, path.nodes[path.nodes.len() - 1], false, mode).map(|link| link.0));
+        edges.windows(2).fold(Costs::zero(), |costs, turn| costs + self.turn_cost(turn[0], turn[1], mode))
+    }
+
+    /// Gets the internal ID of the node closest to a longitude and latitude, which can be used
+    /// by the transport mode. If a maximal distance (in kilometers) is given, nodes further away
+    /// than that are not considered.
+    pub fn snap(&self, lon: f64, lat: f64, max_distance: Option<f64>, mode: usize)
+                -> Result<usize, RoutingError> {
+        let nearest = self.rtree.nearest_neighbor_iterator(&Point2::new(lon, lat))
+            .map(|p| p.index)
+            .find(|&i| self.node_valid(i, mode))
+            .filter(|&i| match max_distance {
+                Some(max_distance) => self.dist_to_node(i, lon, lat) <= max_distance,
+                None => true
+            });
+        nearest.ok_or(RoutingError::NoNodeNearby(lon, lat, max_distance.unwrap_or(0.0)))
+    }
+
+    /// Gets the internal IDs of the `k` nodes closest to a longitude and latitude, together
+    /// with their distances (in kilometers), ordered by their distance.
+    pub fn nearest_nodes(&self, lon: f64, lat: f64, k: usize) -> Vec<(usize, f64)> {
+        self.rtree.nearest_n_neighbors(&Point2::new(lon, lat), k).iter()
+            .map(|p| (p.index, self.dist_to_node(p.index, lon, lat)))
+            .collect()
+    }
+
+    /// Gets the internal IDs of all nodes within a distance (in kilometers) of a longitude
+    /// and latitude, together with their distances, ordered by their distance.
+    pub fn nodes_within(&self, lon: f64, lat: f64, distance: f64) -> Vec<(usize, f64)> {
+        // The R tree works on degrees, so we look up a circle that is large enough in
+        // both directions, and then filter by the actual distance.
+        let radius = distance / (111.32 * lat.to_radians().cos().max(0.01));
+        let mut nodes = self.rtree.lookup_in_circle(&Point2::new(lon, lat), &(radius * radius)).iter()
+            .map(|p| (p.index, self.dist_to_node(p.index, lon, lat)))
+            .filter(|&(_, d)| d <= distance)
+            .collect::<Vec<_>>();
+        nodes.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        nodes
+    }
+
+    /// Computes the Haversine distance (in kilometers) from a node to a longitude and latitude.
+    fn dist_to_node(&self, id: usize, lon: f64, lat: f64) -> f64 {
+        self.dist_to_node_at(&self.nodes[id], lon, lat)
+    }
+
+    /// Computes the Haversine distance (in kilometers) from any node to a longitude and latitude.
+    fn dist_to_node_at(&self, node: &Node, lon: f64, lat: f64) -> f64 {
+        node.dist_to(&Node { id: 0, lon: lon, lat: lat, elevation: 0.0 })
     }
 
     /// Gets the internal ID from an OSM id.
-    fn get_id_from_osm(&self, osm_id: i64) -> usize {
-        self.nodes.iter().position(|r| r.id == osm_id).unwrap()
+    pub fn get_id_from_osm(&self, osm_id: i64) -> Result<usize, RoutingError> {
+        self.osm_index.get(&osm_id).cloned().ok_or(RoutingError::UnknownOsmId(osm_id))
     }
 
     /// Gets the location from an internal id. Returns a vector containing
     /// longitude and latitude.
-    fn get_loc_from_id(&self, id: usize) -> Vec<f64> {
+    pub fn get_loc_from_id(&self, id: usize) -> Vec<f64> {
         vec![self.nodes[id].lon, self.nodes[id].lat]
     }
 
-    /// Performs a routing request from source to target.
-    pub fn route(&self, source: i64, target: i64) -> (Vec<Vec<f64>>, f32) {
-        let source_id = self.get_id_from_osm(source);
-        let target_id = self.get_id_from_osm(target);
-        match self.djikstra(source_id, target_id) {
-            Some((pred, dist)) => {
-                let max_length = self.edges.len();
-
-                println!(" ˪— Backtracking from {}, having {} edges. Total cost: {}.",
-                         target_id, max_length, dist[target_id]);
-                let mut trace = Vec::new();
-                let mut current_node = target_id;
-                trace.push(self.get_loc_from_id(current_node));
-                let mut count = 0;
-                while current_node != source_id {
-                    current_node = pred[current_node];
-                    trace.push(self.get_loc_from_id(current_node));
-
-                    count = count + 1;
-                    // Make sure this doesn't run forever.
-                    if count > max_length {
-                        current_node = source_id;
-                    }
+    /// Performs a routing request from source to target, using the given algorithm, which
+    /// minimizes the cost in terms of the weighting. Source and target are virtual nodes, which
+    /// are either located at nodes of the graph, or linked to them by partial edges. If the
+    /// weighting has negative edge weights (as determined once per request by
+    /// `has_negative_weights`), only Djikstra is supported, which then runs Bellman Ford. Returns
+    /// the trace and the total costs of the route in terms of every metric, or an error if the
+    /// algorithm does not support the edge weights.
+    pub fn route(&self, source: &VirtualNode, target: &VirtualNode, algorithm: RoutingAlgorithm,
+                 weighting: Weighting, negative_weights: bool)
+                 -> Result<(Vec<Vec<f64>>, Costs), RoutingError> {
+        let metric = weighting.metric;
+        let sources = source.outgoing.iter().map(|&(n, c)| (n, c.get(metric))).collect::<Vec<_>>();
+        let targets = target.incoming.iter().map(|&(n, c)| (n, c.get(metric))).collect::<Vec<_>>();
+        // All algorithms except Djikstra stop (or prune their search) as soon as the best path
+        // cannot improve any further, which requires non-negative edge weights. If some weights
+        // are negative (e.g., for energy that is recuperated), Djikstra is replaced by Bellman
+        // Ford, and the other algorithms are rejected.
+        let result = match algorithm {
+            RoutingAlgorithm::Djikstra if negative_weights => {
+                println!(" ˪— The edge weights are partly negative, using Bellman Ford.");
+                match self.bellman_route(&sources, &targets, weighting) {
+                    Ok(path) => path,
+                    Err(err) => return Err(err)
                 }
-
-                (trace, dist[target_id])
             },
-            None => (Vec::new(), 0 as f32)
-        }
-    }
-
-    /// Computes the reachability of all nodes in the graph, and returns those which
-    /// are reachable. Returns a vector of vectors, where the coordinates are as follows:
-    /// longitude, latitude, remaining_energy.
-    pub fn reachability(&self, source: i64, capacity: f32) -> Vec<Vec<f64>> {
-        let source_id = self.get_id_from_osm(source);
-        match self.bellman(source_id) {
-            Some((pred, dist)) => {
-                let max_length = self.nodes.len();
-
-                println!(" ˪— Assessing all {} nodes to select feasible ones.", max_length);
-                let mut trace = Vec::new();
-                for (i, node) in dist.iter().enumerate() {
-                    if capacity - node >= 0.0 {
-                        let mut loc = self.get_loc_from_id(i);
-                        loc.push((capacity - node) as f64);
-                        trace.push(loc);
-                    }
+            _ if negative_weights => return Err(RoutingError::NegativeWeights(algorithm)),
+            RoutingAlgorithm::Djikstra => self.djikstra(&sources, &targets, weighting),
+            RoutingAlgorithm::BidirectionalDjikstra => self.djikstra_double(&sources, &targets, weighting),
+            RoutingAlgorithm::AStar => self.a_star(&sources, &targets, &target.node, weighting),
+            RoutingAlgorithm::TurnAwareDjikstra => self.turn_aware_djikstra(
+                &self.turn_links(source, true, weighting), &self.turn_links(target, false, weighting), weighting),
+            RoutingAlgorithm::ContractionHierarchies => match self.hierarchy(weighting) {
+                Some(hierarchy) => hierarchy.query(&sources, &targets)
+                    .map(|(start, start_cost, edges)| self.path_from_edges(start, start_cost, edges, weighting)),
+                None => {
+                    println!(" ˪— No contraction hierarchy loaded, using bidirectional Djikstra.");
+                    self.djikstra_double(&sources, &targets, weighting)
                 }
+            },
+        };
+
+        // The path through the graph has to be completed by the partial edges leading from the
+        // source and to the target, and compared to the direct connection, if both lie on the
+        // same edge.
+        let result = result.map(|path| {
+            let first = path.nodes[0];
+            let last = path.nodes[path.nodes.len() - 1];
+            let mut costs = path.edges.iter()
+                .fold(cheapest_link(&source.outgoing, first, metric),
+                      |costs, &e| costs + self.costs_for(e, weighting).unwrap())
+                + cheapest_link(&target.incoming, last, metric);
+            if algorithm == RoutingAlgorithm::TurnAwareDjikstra {
+                costs = costs + self.path_turn_costs(source, target, &path, weighting.mode);
+            }
+            (path, costs)
+        });
+        let direct = self.direct_route(source, target, weighting);
+        let use_direct = match (&result, &direct) {
+            (&Some((_, costs)), &Some((_, direct))) => direct.get(metric) < costs.get(metric),
+            (&None, &Some(_)) => true,
+            _ => false
+        };
+        if use_direct {
+            let (trace, direct) = direct.unwrap();
+            println!(" ˪— Source and target lie on the same edge. Total cost: {}.", direct.get(metric));
+            return Ok((trace, direct));
+        }
 
-                trace
+        match result {
+            Some((path, costs)) => {
+                println!(" ˪— Found path having {} edges. Total cost: {}.", path.edges.len(), costs.get(metric));
+                let trace = self.route_trace(source, target, path.nodes[0], &path.edges, weighting.mode);
+
+                Ok((trace, costs))
             },
-            None => Vec::new()
+            None => Ok((Vec::new(), Costs::zero()))
         }
     }
 
-    /// Runs the Bellman Ford algorithm on the graph. Returns a tuple, containing a vector of
-    /// predecessors and a vector of distances to the source node.
-    fn bellman(&self, source: usize) -> Option<(Vec<usize>, Vec<f32>)> {
+    /// Computes the Pareto front of routes from source to target in terms of duration and energy
+    /// for the transport mode (and vehicle profile) of a weighting, i.e., all routes for which no
+    /// other route is both faster and needs less energy. This runs a multi-criteria label-setting
+    /// search, which keeps a set of labels (i.e., ways of reaching a node) per node and discards
+    /// labels dominated by another label at the same node. Labels are settled in the order of their
+    /// duration, which is never negative, whereas the energy may well be. As the front can get
+    /// large, routes taking more than `max_detour` times as long as the fastest one are not
+    /// considered. Returns the trace and the total costs of every route of the front, ordered by
+    /// duration.
+    pub fn pareto_route(&self, source: &VirtualNode, target: &VirtualNode, weighting: Weighting, max_detour: f32)
+                        -> Result<Vec<(Vec<Vec<f64>>, Costs)>, RoutingError> {
+        let mut labels = Vec::new();
+        let mut bags = vec![Vec::new(); self.nodes.len()];
+        let mut heap = BinaryHeap::new();
+        for &(node, costs) in &source.outgoing {
+            add_label(Label { node: node, costs: costs, pred: None, edge: None, dominated: false },
+                      &mut labels, &mut bags, &mut heap);
+        }
+
+        // The routes found, given by the label at their last node (or `None` for the direct
+        // connection, if source and target lie on the same edge) and their total costs.
+        let mut routes = Vec::new();
+        let mut max_duration = std::f32::MAX;
+        let mut direct_trace = Vec::new();
+        let fastest = Weighting { metric: Metric::Duration, ..weighting };
+        if let Some((trace, direct)) = self.direct_route(source, target, fastest) {
+            max_duration = direct.duration * max_detour;
+            routes.push((None, direct));
+            direct_trace = trace;
+        }
+
+        let mut count = 0;
+        while let Some(State { cost, position }) = heap.pop() {
+            if cost > max_duration { break; }
+            if labels[position].dominated { continue; }
+            if labels.len() > PARETO_MAX_LABELS {
+                println!(" ˪— Stopping after {} labels.", labels.len());
+                break;
+            }
+            count = count + 1;
+
+            let label = labels[position];
+            for &(node, link) in &target.incoming {
+                if node == label.node {
+                    let costs = label.costs + link;
+                    max_duration = max_duration.min(costs.duration * max_detour);
+                    routes.push((Some(position), costs));
+                }
+            }
+
+            for &e in self.outgoing(label.node) {
+                let costs = match self.costs_for(e, weighting) {
+                    Some(costs) => costs,
+                    None => continue
+                };
+                let next = Label {
+                    node: self.edges[e].target as usize,
+                    costs: label.costs + costs,
+                    pred: Some(position),
+                    edge: Some(e),
+                    dominated: false,
+                };
+                add_label(next, &mut labels, &mut bags, &mut heap);
+            }
+        }
+        println!(" ˪— Settled {} of {} labels.", count, labels.len());
+
+        // A route reaching the target via one node may still be dominated by a route reaching
+        // it via another one, so the front is only determined once all routes are known.
+        routes.sort_by(|a, b| a.1.duration.partial_cmp(&b.1.duration).unwrap_or(Ordering::Equal)
+            .then(a.1.energy.partial_cmp(&b.1.energy).unwrap_or(Ordering::Equal)));
+        let mut front = Vec::new();
+        let mut min_energy = std::f32::MAX;
+        for (label, costs) in routes {
+            if costs.energy >= min_energy {
+                continue;
+            }
+            min_energy = costs.energy;
+
+            let trace = match label {
+                Some(label) => {
+                    let (first, edges) = self.label_edges(label, &labels);
+                    self.route_trace(source, target, first, &edges, weighting.mode)
+                },
+                None => direct_trace.clone()
+            };
+            front.push((trace, costs));
+        }
+        println!(" ˪— Found {} Pareto-optimal routes.", front.len());
+
+        Ok(front)
+    }
+
+    /// Backtracks from a label of a Pareto search, following the labels from which each label
+    /// was created. Returns the node of the first label, and the edges between the labels, from
+    /// source to target.
+    fn label_edges(&self, label: usize, labels: &[Label]) -> (usize, Vec<usize>) {
+        let mut edges = Vec::new();
+        let mut current = label;
+        while let Some(pred) = labels[current].pred {
+            edges.push(labels[current].edge.unwrap());
+            current = pred;
+        }
+        edges.reverse();
+
+        (labels[current].node, edges)
+    }
+
+    /// Builds a path from its first node, the cost of reaching it, and the edges along it,
+    /// using the edge weights of the weighting.
+    fn path_from_edges(&self, start: usize, start_cost: f32, edges: Vec<usize>, weighting: Weighting) -> Path {
+        let mut nodes = vec![start];
+        let mut costs = vec![start_cost];
+        for &e in &edges {
+            let edge = &self.edges[e];
+            let cost = costs[costs.len() - 1] + self.weight(e, weighting).unwrap();
+            nodes.push(edge.target as usize);
+            costs.push(cost);
+        }
+
+        Path { nodes: nodes, edges: edges, costs: costs }
+    }
+
+    /// Backtracks from the target, following the edge through which each node was reached,
+    /// until a node without predecessor (i.e., one of the sources) is found. Returns `None` if
+    /// the predecessors contain a cycle.
+    fn backtrack(&self, target: usize, pred: &Vec<Option<usize>>, dist: &Vec<f32>, weighting: Weighting)
+                 -> Option<Path> {
+        let mut edges = Vec::new();
+        let mut current_node = target;
+        while let Some(e) = pred[current_node] {
+            edges.push(e);
+            current_node = self.edges[e].source as usize;
+
+            // Make sure this doesn't run forever.
+            if edges.len() > self.nodes.len() {
+                return None;
+            }
+        }
+        edges.reverse();
+
+        Some(self.path_from_edges(current_node, dist[current_node], edges, weighting))
+    }
+
+    /// Performs an energy-constrained routing request from source to target (given as internal
+    /// ids), for a vehicle with the given battery capacity that starts fully charged. Edge weights
+    /// of the weighting (usually the energy of a transport mode) are interpreted as energy, and may
+    /// be negative (recuperation). Returns the trace, the energy used, and the energy remaining at
+    /// the target. If the target cannot be reached without running out of energy, the trace is
+    /// empty.
+    pub fn energy_route(&self, source_id: usize, target_id: usize, capacity: f32, weighting: Weighting)
+                        -> Result<(Vec<Vec<f64>>, f32, f32), RoutingError> {
+        let (pred, charge) = self.energy_bellman(source_id, capacity, weighting);
+        if charge[target_id] < 0.0 {
+            return Ok((Vec::new(), 0.0, 0.0));
+        }
+        let max_length = self.nodes.len();
+
+        println!(" ˪— Backtracking from {}. Remaining charge: {}.",
+                 target_id, charge[target_id]);
+        let mut edges = Vec::new();
+        let mut current_node = target_id;
+        while let Some(e) = pred[current_node] {
+            edges.push(e);
+            current_node = self.edges[e].source as usize;
+
+            // Make sure this doesn't run forever.
+            if edges.len() > max_length {
+                break;
+            }
+        }
+        edges.reverse();
+
+        Ok((self.edges_trace(current_node, &edges), capacity - charge[target_id], charge[target_id]))
+    }
+
+    /// Computes the reachability of all nodes in the graph for a weighting, and returns
+    /// those which are reachable. If the weighting has no negative edge weights, this runs
+    /// Djikstra, which stops once the capacity is exceeded. Otherwise, it runs Bellman Ford: for
+    /// the energy, the energy-constrained variant, which clamps the charge to the capacity, and
+    /// for the other metrics the plain one, which fails if there is a negative cycle. Returns a
+    /// vector of vectors, where the coordinates are as follows: longitude, latitude,
+    /// remaining_energy, together with the algorithm that was used.
+    pub fn reachability(&self, source_id: usize, capacity: f32, weighting: Weighting)
+                        -> Result<(Vec<Vec<f64>>, ReachabilityAlgorithm), RoutingError> {
+        let (algorithm, remaining) = if !self.has_negative_weights(weighting) {
+            let dist = self.bounded_djikstra(source_id, capacity, weighting);
+            (ReachabilityAlgorithm::Djikstra, dist.iter().map(|d| capacity - d).collect::<Vec<_>>())
+        } else if weighting.metric == Metric::Energy {
+            // A battery cannot be charged beyond its capacity, so recuperated energy does not
+            // accumulate, not even along a negative cycle.
+            (ReachabilityAlgorithm::BellmanFord, self.energy_bellman(source_id, capacity, weighting).1)
+        } else {
+            match self.bellman(&[(source_id, 0.0)], weighting) {
+                Ok((_, dist)) => (ReachabilityAlgorithm::BellmanFord, dist.iter().map(|d| capacity - d).collect::<Vec<_>>()),
+                Err(err) => return Err(err)
+            }
+        };
+
+        println!(" ˪— Assessing all {} nodes to select feasible ones.", remaining.len());
+        let mut trace = Vec::new();
+        for (i, &remaining) in remaining.iter().enumerate() {
+            if remaining >= 0.0 {
+                let mut loc = self.get_loc_from_id(i);
+                loc.push(remaining as f64);
+                trace.push(loc);
+            }
+        }
+
+        Ok((trace, algorithm))
+    }
+
+    /// Checks whether any edge that can be used by the transport mode of a weighting has a
+    /// negative weight. This is preprocessed, except for the energy of a vehicle profile, which
+    /// is only known at query time and requires looking at every edge.
+    pub fn has_negative_weights(&self, weighting: Weighting) -> bool {
+        match (weighting.vehicle, weighting.metric) {
+            (Some(_), Metric::Energy) => (0..self.edges.len())
+                .any(|e| self.weight(e, weighting).map_or(false, |weight| weight < 0.0)),
+            _ => self.min_costs[weighting.mode].get(weighting.metric) < 0.0
+        }
+    }
+
+    /// Runs the Djikstra algorithm from a source, settling only nodes whose distance is at most
+    /// `max_cost`. This requires non-negative edge weights. Returns a vector of distances to the
+    /// source node, where nodes that were not settled have a distance of `f32::MAX`.
+    fn bounded_djikstra(&self, source: usize, max_cost: f32, weighting: Weighting) -> Vec<f32> {
         let nodes_count = self.nodes.len();
-        let max_length = self.edges.len();
-        println!(" ˪— Starting from {}, having {} nodes.", source, nodes_count);
-        let mut pred = (0..nodes_count).collect::<Vec<_>>();
-        let mut dist = std::iter::repeat(std::f32::MAX).take(nodes_count).collect::<Vec<_>>();
+        let mut dist = vec![std::f32::MAX; nodes_count];
+        let mut heap = BinaryHeap::new();
         dist[source] = 0.0;
+        heap.push(State { cost: 0.0, position: source });
         let mut count = 0;
 
-        let mut improvement = true;
-        while improvement {
-            improvement = false;
-            for edge in &self.edges {
-                let source_dist = dist[edge.source as usize];
-                let target_dist = dist[edge.target as usize];
-
-                if source_dist != std::f32::MAX && source_dist + edge.weight < target_dist {
-                    dist[edge.target as usize] = source_dist + edge.weight;
-                    pred[edge.target as usize] = edge.source as usize;
-                    improvement = true;
+        while let Some(State { cost, position }) = heap.pop() {
+            if cost > max_cost { break; }
+            if cost > dist[position] { continue; }
+            count = count + 1;
+
+            for &e in self.outgoing(position) {
+                let weight = match self.weight(e, weighting) {
+                    Some(weight) => weight,
+                    None => continue
+                };
+                let next = self.edges[e].target as usize;
+                if cost + weight < dist[next] {
+                    dist[next] = cost + weight;
+                    heap.push(State { cost: dist[next], position: next });
                 }
+            }
+        }
+        println!(" ˪— Djikstra settled {} nodes.", count);
 
-                // This would be needed for undirected edges, as we'd have to follow every
-                // edge both ways in that case.
-                // if target_dist != std::f32::MAX && target_dist + edge.weight < source_dist {
-                //     dist[edge.source as usize] = target_dist + edge.weight;
-                //     pred[edge.source as usize] = edge.target as usize;
-                //     improvement = true;
-                // }
+        // Nodes that were reached, but not settled, exceed the maximal cost.
+        for d in dist.iter_mut() {
+            if *d > max_cost {
+                *d = std::f32::MAX;
+            }
+        }
+        dist
+    }
+
+    /// Runs the Bellman Ford algorithm on the graph, starting at all sources (with the cost given
+    /// for each of them). Nodes whose distance improved are kept in a queue, so that only their
+    /// outgoing edges have to be relaxed again. Returns a tuple, containing a vector of the edges
+    /// through which every node was reached and a vector of distances to the closest source node,
+    /// or an error naming the nodes and edges of a negative cycle, if one can be reached from the
+    /// sources.
+    fn bellman(&self, sources: &[(usize, f32)], weighting: Weighting)
+               -> Result<(Vec<Option<usize>>, Vec<f32>), RoutingError> {
+        let nodes_count = self.nodes.len();
+        println!(" ˪— Starting from {} nodes, having {} nodes.", sources.len(), nodes_count);
+        let mut pred = vec![None; nodes_count];
+        let mut dist = std::iter::repeat(std::f32::MAX).take(nodes_count).collect::<Vec<_>>();
+        let mut in_queue = vec![false; nodes_count];
+        let mut visits = vec![0; nodes_count];
+        let mut queue = VecDeque::new();
+        for &(source, cost) in sources {
+            if cost < dist[source] {
+                dist[source] = cost;
+            }
+            if !in_queue[source] {
+                queue.push_back(source);
+                in_queue[source] = true;
             }
+        }
+        let mut count = 0;
+
+        while let Some(position) = queue.pop_front() {
+            in_queue[position] = false;
             count = count + 1;
 
-            // Make sure this doesn't run forever.
-            if count > max_length {
-                improvement = false;
+            // A node can only be improved more than `nodes_count` times if there is a negative
+            // cycle, which shows up as a cycle in the predecessors sooner or later.
+            visits[position] += 1;
+            if visits[position] > nodes_count {
+                if let Some(cycle) = self.pred_cycle(position, &pred) {
+                    println!(" ˪— Found a negative cycle of {} edges after {} iterations.", cycle.len(), count);
+                    let nodes = cycle.iter().map(|&e| self.nodes[self.edges[e].source as usize].id).collect();
+                    return Err(RoutingError::NegativeCycle(nodes, cycle));
+                }
+            }
+
+            let source_dist = dist[position];
+            for &e in self.outgoing(position) {
+                let weight = match self.weight(e, weighting) {
+                    Some(weight) => weight,
+                    None => continue
+                };
+                let next = self.edges[e].target as usize;
+                if source_dist + weight < dist[next] {
+                    dist[next] = source_dist + weight;
+                    pred[next] = Some(e);
+                    if !in_queue[next] {
+                        in_queue[next] = true;
+                        queue.push_back(next);
+                    }
+                }
             }
         }
         println!(" ˪— Bellman iterations: {}", count);
 
-        Some((pred, dist))
+        Ok((pred, dist))
+    }
+
+    /// Runs Bellman Ford from the sources (with the cost given for each of them), and returns the
+    /// shortest path to the target with the lowest cost (including the cost given for each
+    /// target), or `None` if no target can be reached. Unlike Djikstra, this allows negative edge
+    /// weights, but fails if the sources can reach a negative cycle.
+    fn bellman_route(&self, sources: &[(usize, f32)], targets: &[(usize, f32)], weighting: Weighting)
+                     -> Result<Option<Path>, RoutingError> {
+        let (pred, dist) = match self.bellman(sources, weighting) {
+            Ok(result) => result,
+            Err(err) => return Err(err)
+        };
+        let best_target = targets.iter()
+            .filter(|&&(target, _)| dist[target] < std::f32::MAX)
+            .min_by(|a, b| (dist[a.0] + a.1).partial_cmp(&(dist[b.0] + b.1)).unwrap_or(Ordering::Equal))
+            .map(|&(target, _)| target);
+
+        Ok(best_target.and_then(|target| self.backtrack(target, &pred, &dist, weighting)))
     }
 
-    /// Runs the Djikstra algorithm on the graph. Returns a tuple, containing a vector of
-    /// predecessors and a vector of distances to the source node.
-    fn djikstra(&self, source: usize, target: usize) -> Option<(Vec<usize>, Vec<f32>)> {
-        let mut dists: HashMap<usize, f32> = HashMap::new();
+    /// Follows the edges through which the nodes were reached backwards from a node, and returns
+    /// the edges of the cycle found this way (in the order of the cycle), if any.
+    fn pred_cycle(&self, node: usize, pred: &Vec<Option<usize>>) -> Option<Vec<usize>> {
+        let mut steps = HashMap::new();
+        let mut edges = Vec::new();
+        let mut current = node;
+        while !steps.contains_key(&current) {
+            steps.insert(current, edges.len());
+            match pred[current] {
+                Some(e) => {
+                    edges.push(e);
+                    current = self.edges[e].source as usize;
+                },
+                None => return None
+            }
+        }
+
+        let mut cycle = edges.split_off(steps[&current]);
+        cycle.reverse();
+        Some(cycle)
+    }
+
+    /// Checks the edge weights of a weighting for negative cycles anywhere in the graph, by
+    /// running Bellman Ford from all nodes at once.
+    pub fn validate(&self, weighting: Weighting) -> Result<(), RoutingError> {
+        if !self.has_negative_weights(weighting) {
+            return Ok(());
+        }
+        let sources = (0..self.nodes.len()).map(|node| (node, 0.0)).collect::<Vec<_>>();
+        self.bellman(&sources, weighting).map(|_| ())
+    }
+
+    /// Runs an energy-constrained variant of the Bellman Ford algorithm on the graph. Instead of
+    /// minimizing the distance, it maximizes the remaining charge at every node, where the charge
+    /// is clamped to `capacity` (a battery cannot be charged any further) and edges that would
+    /// drain the battery below 0 cannot be traversed. Because of the clamping, negative edge
+    /// weights and even negative cycles are handled correctly. Returns a tuple, containing a
+    /// vector of the edges through which the nodes were reached and a vector of remaining
+    /// charges, where unreachable nodes have a negative charge.
+    pub fn energy_bellman(&self, source: usize, capacity: f32, weighting: Weighting)
+                          -> (Vec<Option<usize>>, Vec<f32>) {
+        let nodes_count = self.nodes.len();
+        let mut pred = vec![None; nodes_count];
+        let mut charge = std::iter::repeat(-1.0).take(nodes_count).collect::<Vec<f32>>();
+        let mut in_queue = vec![false; nodes_count];
+        let mut visits = vec![0; nodes_count];
+        let mut queue = VecDeque::new();
+        charge[source] = capacity;
+        queue.push_back(source);
+        in_queue[source] = true;
+
+        while let Some(position) = queue.pop_front() {
+            in_queue[position] = false;
+
+            // Make sure this doesn't run forever.
+            visits[position] += 1;
+            if visits[position] > nodes_count {
+                break;
+            }
+
+            let source_charge = charge[position];
+            for &e in self.outgoing(position) {
+                let weight = match self.weight(e, weighting) {
+                    Some(weight) => weight,
+                    None => continue
+                };
+                let next = self.edges[e].target as usize;
+                let target_charge = (source_charge - weight).min(capacity);
+                if target_charge >= 0.0 && target_charge > charge[next] {
+                    charge[next] = target_charge;
+                    pred[next] = Some(e);
+                    if !in_queue[next] {
+                        in_queue[next] = true;
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+
+        (pred, charge)
+    }
+
+    /// Runs the Djikstra algorithm on the graph, starting at the sources (with the cost given
+    /// for each of them), and ending at the target with the lowest cost (including the cost
+    /// given for each target). Only edges that can be used by the transport mode are followed,
+    /// and their weights must not be negative. Returns the shortest path, or `None` if no target
+    /// can be reached.
+    fn djikstra(&self, sources: &[(usize, f32)], targets: &[(usize, f32)], weighting: Weighting) -> Option<Path> {
+        let nodes_count = self.nodes.len();
+        let mut pred = vec![None; nodes_count];
+        let mut dist = vec![std::f32::MAX; nodes_count];
         let mut heap = BinaryHeap::new();
 
-        dists.insert(source, 0.0);
-        heap.push(State { cost: 0.0, position: source });
+        for &(source, cost) in sources {
+            if cost < dist[source] {
+                dist[source] = cost;
+                heap.push(State { cost: cost, position: source });
+            }
+        }
 
+        let mut best = std::f32::MAX;
+        let mut best_target = None;
         while let Some(State { cost, position }) = heap.pop() {
-            if position == target {
-                let mut predecessors = Vec::new();
-                let mut distances = Vec::new();
-                for (key, val) in dists.iter_mut() {
-                    predecessors.push(*key);
-                    distances.push(*val);
+            if cost >= best { break; }
+            if cost > dist[position] { continue; }
+
+            for &(target, target_cost) in targets {
+                if target == position && cost + target_cost < best {
+                    best = cost + target_cost;
+                    best_target = Some(target);
+                }
+            }
+
+            for &e in self.outgoing(position) {
+                let weight = match self.weight(e, weighting) {
+                    Some(weight) => weight,
+                    None => continue
+                };
+                let next = self.edges[e].target as usize;
+                if cost + weight < dist[next] {
+                    dist[next] = cost + weight;
+                    pred[next] = Some(e);
+                    heap.push(State { cost: dist[next], position: next });
                 }
-                return Some((predecessors, distances));
-            }
-            if cost > dists[&position] { continue; }
-
-            for edge in &self.edges {
-                if edge.source == position as i64 {
-                    let next = State { cost: cost + edge.weight, position: edge.target as usize };
-                    if !dists.contains_key(&next.position) {
-                        dists.insert(next.position, next.cost);
-                        heap.push(next);
-                    } else if next.cost < dists[&next.position] {
-                        dists.insert(next.position, next.cost);
-                        heap.push(next);
+            }
+        }
+
+        best_target.and_then(|target| self.backtrack(target, &pred, &dist, weighting))
+    }
+
+    /// Returns the links between a virtual node and the graph for the turn-aware search, i.e.,
+    /// the nodes the virtual node is linked to (or, if `outgoing` is false, from), the weights of
+    /// the partial edges, and the edges they are part of, if any.
+    fn turn_links(&self, node: &VirtualNode, outgoing: bool, weighting: Weighting) -> Vec<(usize, f32, Option<usize>)> {
+        match node.edge {
+            Some((edge, fraction)) => self.parallel_edges(edge, fraction, weighting.mode).into_iter()
+                .map(|(e, f)| {
+                    let weight = self.weight(e, weighting).unwrap() as f64;
+                    if outgoing {
+                        (self.edges[e].target as usize, (weight * (1.0 - f)) as f32, Some(e))
+                    } else {
+                        (self.edges[e].source as usize, (weight * f) as f32, Some(e))
                     }
+                })
+                .collect(),
+            None => {
+                let links = if outgoing { &node.outgoing } else { &node.incoming };
+                links.iter().map(|&(n, c)| (n, c.get(weighting.metric), None)).collect()
+            }
+        }
+    }
+
+    /// Runs Djikstra on the edges of the graph instead of its nodes (i.e., on the edge-expanded
+    /// graph), which allows to honour turn restrictions and to add the turn costs of the transport
+    /// mode to every turn. A search state is an edge, together with the position in the turn
+    /// restrictions matched by the edges leading to it, so that every turn completing a restricted
+    /// sequence is skipped. Sources and targets are given as node, cost, and the edge of the partial
+    /// edge linking them to their virtual node (if any), so that turns from and onto partial edges
+    /// are restricted as well. Returns the shortest path, or `None` if no target can be reached.
+    fn turn_aware_djikstra(&self, sources: &[(usize, f32, Option<usize>)], targets: &[(usize, f32, Option<usize>)],
+                           weighting: Weighting) -> Option<Path> {
+        let mode = weighting.mode;
+        let restrictions = &self.turn_restrictions;
+        let turn = |from: usize, to: usize| self.turn_cost(from, to, mode).get(weighting.metric);
+        let mut labels: Vec<TurnLabel> = Vec::new();
+        let mut dist: HashMap<(usize, usize), f32> = HashMap::new();
+        let mut heap = BinaryHeap::new();
+
+        // A path may also consist of a single node, which links the source to the target.
+        let mut best = std::f32::MAX;
+        let mut best_node = None;
+        let mut best_label = None;
+        for &(source, cost, link) in sources {
+            let position = link.map_or(0, |l| restrictions.start(l));
+            for &(target, target_cost, target_link) in targets {
+                let (allowed, turn_cost) = match (link, target_link) {
+                    (Some(l), Some(t)) => (restrictions.turn(position, t, mode).is_some(), turn(l, t)),
+                    _ => (true, 0.0)
+                };
+                if target == source && allowed && cost + turn_cost + target_cost < best {
+                    best = cost + turn_cost + target_cost;
+                    best_node = Some((source, cost));
                 }
             }
+
+            for &e in self.outgoing(source) {
+                let weight = match self.weight(e, weighting) {
+                    Some(weight) => weight,
+                    None => continue
+                };
+                let (restriction, turn_cost) = match link {
+                    Some(l) => match restrictions.turn(position, e, mode) {
+                        Some(restriction) => (restriction, turn(l, e)),
+                        None => continue
+                    },
+                    None => (restrictions.start(e), 0.0)
+                };
+                let label = TurnLabel { edge: e, restriction: restriction, cost: cost + turn_cost + weight, pred: None };
+                add_turn_label(label, &mut labels, &mut dist, &mut heap);
+            }
         }
 
-        None
+        while let Some(State { cost, position }) = heap.pop() {
+            if cost >= best { break; }
+            let label = labels[position];
+            if cost > dist[&(label.edge, label.restriction)] { continue; }
+
+            let node = self.edges[label.edge].target as usize;
+            for &(target, target_cost, link) in targets {
+                let allowed = link.map_or(true, |l| restrictions.turn(label.restriction, l, mode).is_some());
+                let turn_cost = link.map_or(0.0, |l| turn(label.edge, l));
+                if target == node && allowed && cost + turn_cost + target_cost < best {
+                    best = cost + turn_cost + target_cost;
+                    best_node = None;
+                    best_label = Some(position);
+                }
+            }
+
+            for &e in self.outgoing(node) {
+                let weight = match self.weight(e, weighting) {
+                    Some(weight) => weight,
+                    None => continue
+                };
+                let restriction = match restrictions.turn(label.restriction, e, mode) {
+                    Some(restriction) => restriction,
+                    None => continue
+                };
+                let next = TurnLabel {
+                    edge: e,
+                    restriction: restriction,
+                    cost: cost + turn(label.edge, e) + weight,
+                    pred: Some(position),
+                };
+                add_turn_label(next, &mut labels, &mut dist, &mut heap);
+            }
+        }
+
+        if let Some((node, cost)) = best_node {
+            return Some(Path { nodes: vec![node], edges: Vec::new(), costs: vec![cost] });
+        }
+        best_label.map(|label| {
+            let mut edges = vec![labels[label].edge];
+            let mut current = label;
+            while let Some(pred) = labels[current].pred {
+                edges.push(labels[pred].edge);
+                current = pred;
+            }
+            edges.reverse();
+            let start_cost = labels[current].cost - self.weight(edges[0], weighting).unwrap();
+            self.path_from_edges(self.edges[edges[0]].source as usize, start_cost, edges, weighting)
+        })
     }
 
     /// Runs Djikstra from both sides, resulting in half as many computations (as they "meet"
     /// in the middle).
-    fn djikstra_double(&self, source: usize, target: usize) -> Option<(Vec<usize>, Vec<f32>)> {
-        None
+    ///
+    /// The forward search runs on outgoing edges from the source, the backward search on
+    /// incoming edges from the target, always advancing the side with the smaller tentative
+    /// distance. Both searches stop as soon as the sum of their smallest tentative distances
+    /// exceeds the best path found so far, which requires non-negative edge weights.
+    fn djikstra_double(&self, sources: &[(usize, f32)], targets: &[(usize, f32)], weighting: Weighting)
+                       -> Option<Path> {
+        let nodes_count = self.nodes.len();
+        let mut dist_fwd = vec![std::f32::MAX; nodes_count];
+        let mut dist_bwd = vec![std::f32::MAX; nodes_count];
+        let mut pred = vec![None; nodes_count];
+        let mut succ = vec![None; nodes_count];
+        let mut heap_fwd = BinaryHeap::new();
+        let mut heap_bwd = BinaryHeap::new();
+
+        for &(source, cost) in sources {
+            if cost < dist_fwd[source] {
+                dist_fwd[source] = cost;
+                heap_fwd.push(State { cost: cost, position: source });
+            }
+        }
+        for &(target, cost) in targets {
+            if cost < dist_bwd[target] {
+                dist_bwd[target] = cost;
+                heap_bwd.push(State { cost: cost, position: target });
+            }
+        }
+
+        let mut best = std::f32::MAX;
+        let mut meeting = None;
+        for &(source, _) in sources {
+            if dist_bwd[source] != std::f32::MAX && dist_fwd[source] + dist_bwd[source] < best {
+                best = dist_fwd[source] + dist_bwd[source];
+                meeting = Some(source);
+            }
+        }
+
+        loop {
+            let top_fwd = heap_fwd.peek().map(|s| s.cost).unwrap_or(std::f32::MAX);
+            let top_bwd = heap_bwd.peek().map(|s| s.cost).unwrap_or(std::f32::MAX);
+            if top_fwd == std::f32::MAX || top_bwd == std::f32::MAX || top_fwd + top_bwd >= best {
+                break;
+            }
+
+            if top_fwd <= top_bwd {
+                let State { cost, position } = heap_fwd.pop().unwrap();
+                if cost > dist_fwd[position] { continue; }
+
+                for &e in self.outgoing(position) {
+                    let weight = match self.weight(e, weighting) {
+                        Some(weight) => weight,
+                        None => continue
+                    };
+                    let next = self.edges[e].target as usize;
+                    if cost + weight < dist_fwd[next] {
+                        dist_fwd[next] = cost + weight;
+                        pred[next] = Some(e);
+                        heap_fwd.push(State { cost: dist_fwd[next], position: next });
+                    }
+                    if dist_bwd[next] != std::f32::MAX && dist_fwd[next] + dist_bwd[next] < best {
+                        best = dist_fwd[next] + dist_bwd[next];
+                        meeting = Some(next);
+                    }
+                }
+            } else {
+                let State { cost, position } = heap_bwd.pop().unwrap();
+                if cost > dist_bwd[position] { continue; }
+
+                for &e in self.incoming(position) {
+                    let weight = match self.weight(e, weighting) {
+                        Some(weight) => weight,
+                        None => continue
+                    };
+                    let next = self.edges[e].source as usize;
+                    if cost + weight < dist_bwd[next] {
+                        dist_bwd[next] = cost + weight;
+                        succ[next] = Some(e);
+                        heap_bwd.push(State { cost: dist_bwd[next], position: next });
+                    }
+                    if dist_fwd[next] != std::f32::MAX && dist_fwd[next] + dist_bwd[next] < best {
+                        best = dist_fwd[next] + dist_bwd[next];
+                        meeting = Some(next);
+                    }
+                }
+            }
+        }
+
+        // Join the two halves of the path at the meeting node.
+        let meeting = match meeting {
+            Some(meeting) => meeting,
+            None => return None
+        };
+        let first_half = match self.backtrack(meeting, &pred, &dist_fwd, weighting) {
+            Some(path) => path,
+            None => return None
+        };
+        let mut path = first_half.edges;
+        let mut current_node = meeting;
+        while let Some(e) = succ[current_node] {
+            path.push(e);
+            current_node = self.edges[e].target as usize;
+
+            // Make sure this doesn't run forever.
+            if path.len() > nodes_count {
+                return None;
+            }
+        }
+
+        Some(self.path_from_edges(first_half.nodes[0], first_half.costs[0], path, weighting))
+    }
+
+    /// Runs the A* algorithm on the graph. This is faster than Djikstra in many cases. The
+    /// heuristic is the Haversine distance to the target location, scaled by the minimal cost
+    /// per kilometer of the weighting, which never overestimates the remaining cost. Sources
+    /// and targets are given as for Djikstra. Returns the shortest path, or `None` if no target
+    /// can be reached.
+    fn a_star(&self, sources: &[(usize, f32)], targets: &[(usize, f32)], target_node: &Node,
+              weighting: Weighting) -> Option<Path> {
+        let nodes_count = self.nodes.len();
+        let min_cost_per_km = self.lower_bound_per_km(weighting);
+        let heuristic = |node: usize| {
+            self.nodes[node].dist_to(target_node) as f32 * min_cost_per_km
+        };
+        let mut pred = vec![None; nodes_count];
+        let mut dist = vec![std::f32::MAX; nodes_count];
+        let mut settled = vec![false; nodes_count];
+        let mut heap = BinaryHeap::new();
+
+        for &(source, cost) in sources {
+            if cost < dist[source] {
+                dist[source] = cost;
+                heap.push(State { cost: cost + heuristic(source), position: source });
+            }
+        }
+
+        let mut best = std::f32::MAX;
+        let mut best_target = None;
+        while let Some(State { cost, position }) = heap.pop() {
+            if cost >= best { break; }
+            // As the heuristic is consistent, every node only needs to be expanded once.
+            if settled[position] { continue; }
+            settled[position] = true;
+
+            for &(target, target_cost) in targets {
+                if target == position && dist[position] + target_cost < best {
+                    best = dist[position] + target_cost;
+                    best_target = Some(target);
+                }
+            }
+
+            for &e in self.outgoing(position) {
+                let weight = match self.weight(e, weighting) {
+                    Some(weight) => weight,
+                    None => continue
+                };
+                let next = self.edges[e].target as usize;
+                let next_dist = dist[position] + weight;
+                if !settled[next] && next_dist < dist[next] {
+                    dist[next] = next_dist;
+                    pred[next] = Some(e);
+                    heap.push(State { cost: next_dist + heuristic(next), position: next });
+                }
+            }
+        }
+
+        best_target.and_then(|target| self.backtrack(target, &pred, &dist, weighting))
     }
+}
 
-    /// Runs the A* algorithm on the graph. This is faster than Djikstra in many cases.
-    fn a_star(&self, source: usize, target: usize) -> Option<(Vec<usize>, Vec<f32>)> {
-        None
+/// Computes the minimal edge costs of a transport mode per kilometer of Haversine distance
+/// between the edge's nodes, for every metric.
+fn min_cost_per_km(nodes: &Vec<Node>, edges: &Vec<Edge>, mode: usize) -> Costs {
+    let weighting = |metric| Weighting { mode: mode, metric: metric, vehicle: None };
+    Costs {
+        distance: min_weight_per_km(nodes, edges, weighting(Metric::Distance)),
+        duration: min_weight_per_km(nodes, edges, weighting(Metric::Duration)),
+        energy: min_weight_per_km(nodes, edges, weighting(Metric::Energy)),
     }
 }
 
+/// Computes the minimal edge costs of a transport mode for every metric, or costs of 0 if they
+/// are all positive.
+fn min_costs(edges: &Vec<Edge>, mode: usize) -> Costs {
+    edges.iter().filter_map(|e| e.costs[mode]).fold(Costs::zero(), |min, c| Costs {
+        distance: min.distance.min(c.distance),
+        duration: min.duration.min(c.duration),
+        energy: min.energy.min(c.energy),
+    })
+}
+
+/// Computes the minimal edge weight of a weighting per kilometer of Haversine distance.
+/// Negative values are clamped to 0, as the heuristic would not be admissible otherwise.
+/// The result is reduced slightly, so that rounding errors cannot make it overestimate.
+fn min_weight_per_km(nodes: &Vec<Node>, edges: &Vec<Edge>, weighting: Weighting) -> f32 {
+    let mut min_cost = std::f64::MAX;
+    for edge in edges {
+        let weight = match edge.weight(weighting) {
+            Some(weight) => weight,
+            None => continue
+        };
+        let length = nodes[edge.source as usize].dist_to(&nodes[edge.target as usize]);
+        if weight < 0.0 {
+            return 0.0;
+        } else if length > 0.0 {
+            min_cost = min_cost.min(weight as f64 / length);
+        }
+    }
+
+    if min_cost == std::f64::MAX { 0.0 } else { (min_cost * 0.999) as f32 }
+}
+
+/// Returns the longitudes and latitudes of all points of an edge, i.e., its source, its
+/// geometry and its target.
+fn polyline(nodes: &[Node], edge: &Edge) -> Vec<(f64, f64)> {
+    let source = &nodes[edge.source as usize];
+    let target = &nodes[edge.target as usize];
+    let mut points = vec![(source.lon, source.lat)];
+    points.extend(edge.geometry.iter().cloned());
+    points.push((target.lon, target.lat));
+
+    points
+}
+
+/// Computes the fraction of the length of a polyline (from its start) at which each of its
+/// points lies. If the polyline has no length, the points are spread evenly.
+fn polyline_fractions(points: &[(f64, f64)]) -> Vec<f64> {
+    let node = |&(lon, lat): &(f64, f64)| Node { id: 0, lon: lon, lat: lat, elevation: 0.0 };
+    let mut lengths = vec![0.0];
+    for i in 1..points.len() {
+        let length = lengths[i - 1] + node(&points[i - 1]).dist_to(&node(&points[i]));
+        lengths.push(length);
+    }
+    let total = lengths[lengths.len() - 1];
+    if total > 0.0 {
+        lengths.iter().map(|l| l / total).collect()
+    } else {
+        (0..points.len()).map(|i| i as f64 / (points.len() - 1).max(1) as f64).collect()
+    }
+}
+
+/// Returns the costs of the cheapest partial edge (in terms of a metric) that links a virtual
+/// node to the given node, or zero costs if there is none.
+fn cheapest_link(links: &[(usize, Costs)], node: usize, metric: Metric) -> Costs {
+    links.iter()
+        .filter(|l| l.0 == node)
+        .map(|l| l.1)
+        .fold(None, |min: Option<Costs>, costs| match min {
+            Some(min) if min.get(metric) <= costs.get(metric) => Some(min),
+            _ => Some(costs)
+        })
+        .unwrap_or(Costs::zero())
+}
+
+/// Adds a label to the labels of its node, unless one of them dominates it, in which case it
+/// is discarded. Labels dominated by the new one are marked, so that they are skipped once
+/// they are taken from the heap.
+fn add_label(label: Label, labels: &mut Vec<Label>, bags: &mut Vec<Vec<usize>>, heap: &mut BinaryHeap<State>) {
+    let bag = &mut bags[label.node];
+    if bag.iter().any(|&l| labels[l].dominates(&label)) {
+        return;
+    }
+    for &l in bag.iter() {
+        if label.dominates(&labels[l]) {
+            labels[l].dominated = true;
+        }
+    }
+    bag.retain(|&l| !labels[l].dominated);
+
+    bag.push(labels.len());
+    heap.push(State { cost: label.costs.duration, position: labels.len() });
+    labels.push(label);
+}
+
+/// Adds a label to the turn-aware search, unless its state (edge and restriction position) was
+/// already reached at a lower cost.
+fn add_turn_label(label: TurnLabel, labels: &mut Vec<TurnLabel>, dist: &mut HashMap<(usize, usize), f32>,
+                  heap: &mut BinaryHeap<State>) {
+    let key = (label.edge, label.restriction);
+    if dist.get(&key).map_or(false, |&cost| cost <= label.cost) {
+        return;
+    }
+    dist.insert(key, label.cost);
+    heap.push(State { cost: label.cost, position: labels.len() });
+    labels.push(label);
+}
+
+/// Builds a compressed sparse row index over the edges, grouped by the given node keys (one
+/// key per edge). Returns the offsets (one per node, plus a final one) and the edge indices.
+pub fn build_adjacency<I>(nodes_count: usize, keys: I) -> (Vec<usize>, Vec<usize>)
+    where I: Iterator<Item=usize> + Clone {
+    let mut offsets = vec![0; nodes_count + 1];
+    for key in keys.clone() {
+        offsets[key + 1] += 1;
+    }
+    for i in 0..nodes_count {
+        offsets[i + 1] += offsets[i];
+    }
+
+    let mut next = offsets.clone();
+    let mut edges = vec![0; offsets[nodes_count]];
+    for (e, key) in keys.enumerate() {
+        edges[next[key]] = e;
+        next[key] += 1;
+    }
+
+    (offsets, edges)
+}
+
+/// The errors that can occur when answering routing requests.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoutingError {
+    /// There is no node with the given OSM id in the graph.
+    UnknownOsmId(i64),
+    /// There is no node within the given distance (in kilometers) of a longitude and latitude.
+    NoNodeNearby(f64, f64, f64),
+    /// The edge weights contain a negative cycle, so there are no shortest paths. Contains the
+    /// OSM ids of the nodes and the indices of the edges along the cycle.
+    NegativeCycle(Vec<i64>, Vec<usize>),
+    /// The algorithm requires non-negative edge weights, but some edge weights are negative.
+    NegativeWeights(RoutingAlgorithm),
+    /// A parameter of the request has an invalid value. Contains the name and the value of the
+    /// parameter.
+    InvalidParameter(String, String),
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RoutingError::UnknownOsmId(id) => write!(f, "There is no node with OSM id {}.", id),
+            RoutingError::NoNodeNearby(lon, lat, distance) =>
+                write!(f, "There is no node within {} m of ({}, {}).", distance * 1000.0, lon, lat),
+            RoutingError::NegativeCycle(ref nodes, ref edges) =>
+                write!(f, "The edge weights contain a negative cycle through the nodes with OSM ids {:?} \
+                           (edges {:?}).", nodes, edges),
+            RoutingError::NegativeWeights(algorithm) =>
+                write!(f, "The edge weights are partly negative, which {} does not support (use dijkstra \
+                           instead).", algorithm.name()),
+            RoutingError::InvalidParameter(ref name, ref value) =>
+                write!(f, "The value {:?} of the parameter {} is invalid.", value, name),
+        }
+    }
+}
+
+/// The algorithms that can be used to answer routing requests.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RoutingAlgorithm {
+    /// Djikstra, searching from the source only.
+    Djikstra,
+    /// Djikstra, searching from both source and target.
+    BidirectionalDjikstra,
+    /// A*, using the Haversine distance to the target as heuristic.
+    AStar,
+    /// A bidirectional search on the contraction hierarchy of the graph.
+    ContractionHierarchies,
+    /// Djikstra on the edges of the graph, honouring turn restrictions.
+    TurnAwareDjikstra,
+}
+
+impl RoutingAlgorithm {
+    /// Gets an algorithm from its name, as used in the HTTP API.
+    pub fn from_name(name: &str) -> Option<RoutingAlgorithm> {
+        match name {
+            "dijkstra" => Some(RoutingAlgorithm::Djikstra),
+            "bidirectional-dijkstra" => Some(RoutingAlgorithm::BidirectionalDjikstra),
+            "a-star" => Some(RoutingAlgorithm::AStar),
+            "contraction-hierarchies" => Some(RoutingAlgorithm::ContractionHierarchies),
+            "turn-aware-dijkstra" => Some(RoutingAlgorithm::TurnAwareDjikstra),
+            _ => None
+        }
+    }
+
+    /// Gets the name of an algorithm, as used in the HTTP API.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RoutingAlgorithm::Djikstra => "dijkstra",
+            RoutingAlgorithm::BidirectionalDjikstra => "bidirectional-dijkstra",
+            RoutingAlgorithm::AStar => "a-star",
+            RoutingAlgorithm::ContractionHierarchies => "contraction-hierarchies",
+            RoutingAlgorithm::TurnAwareDjikstra => "turn-aware-dijkstra",
+        }
+    }
+}
+
+/// The algorithms used to compute reachability.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ReachabilityAlgorithm {
+    /// Djikstra, stopping once the capacity is exceeded, for non-negative edge weights.
+    Djikstra,
+    /// Bellman Ford, for edge weights that may be negative.
+    BellmanFord,
+}
+
+impl ReachabilityAlgorithm {
+    /// Gets the name of an algorithm, as used in the HTTP API.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ReachabilityAlgorithm::Djikstra => "dijkstra",
+            ReachabilityAlgorithm::BellmanFord => "bellman-ford",
+        }
+    }
+}
+
+/// The metrics in terms of which routes can be optimized.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Metric {
+    /// The distance along the route.
+    Distance,
+    /// The travel time.
+    Duration,
+    /// The energy used.
+    Energy,
+}
+
+/// All metrics, in the order of their declaration.
+pub const METRICS: [Metric; 3] = [Metric::Distance, Metric::Duration, Metric::Energy];
+
+impl Metric {
+    /// Gets a metric from its name, as used in the HTTP API.
+    pub fn from_name(name: &str) -> Option<Metric> {
+        match name {
+            "distance" => Some(Metric::Distance),
+            "duration" => Some(Metric::Duration),
+            "energy" => Some(Metric::Energy),
+            _ => None
+        }
+    }
+}
+
+/// Determines the edge weights used by a query, i.e., the cost of the edges in terms of a
+/// metric for a transport mode, and optionally a vehicle profile that determines the energy.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Weighting {
+    /// The index of the transport mode.
+    pub mode: usize,
+    /// The metric that is minimized.
+    pub metric: Metric,
+    /// The vehicle profile from which the energy of the edges is computed, or `None` to use the
+    /// energy computed by the transport mode script.
+    pub vehicle: Option<VehicleProfile>,
+}
+
+/// An entry of the priority queues used by the Djikstra-like algorithms.
 #[derive(Copy, Clone, PartialEq)]
-struct State {
-    cost: f32,
-    position: usize,
+pub struct State {
+    /// The cost with which the node was reached.
+    pub cost: f32,
+    /// The node that was reached.
+    pub position: usize,
 }
 
 impl Eq for State {}
 
+/// A label of the Pareto search, i.e., a way of reaching a node.
+#[derive(Debug, Copy, Clone)]
+struct Label {
+    /// The node that was reached.
+    node: usize,
+    /// The costs with which the node was reached.
+    costs: Costs,
+    /// The label from which this one was created, or `None` if the node is linked to the source.
+    pred: Option<usize>,
+    /// The edge through which the node was reached from the previous label, if any.
+    edge: Option<usize>,
+    /// Whether another label at the same node dominates this one.
+    dominated: bool,
+}
+
+/// A label of the turn-aware search, i.e., a way of reaching the end of an edge.
+#[derive(Debug, Copy, Clone)]
+struct TurnLabel {
+    /// The edge that was traversed.
+    edge: usize,
+    /// The position in the turn restrictions matched by the edges leading here.
+    restriction: usize,
+    /// The cost with which the end of the edge was reached.
+    cost: f32,
+    /// The label from which this one was created, or `None` if the edge starts at a source.
+    pred: Option<usize>,
+}
+
+impl Label {
+    /// Checks whether this label is at least as good as another one in terms of both duration
+    /// and energy.
+    fn dominates(&self, other: &Label) -> bool {
+        self.costs.duration <= other.costs.duration && self.costs.energy <= other.costs.energy
+    }
+}
+
 // The priority queue depends on `Ord`.
 // Explicitly implement the trait so the queue becomes a min-heap
 // instead of a max-heap.
@@ -397,4 +1941,428 @@ impl PartialOrd for State {
     fn partial_cmp(&self, other: &State) -> Option<Ordering> {
         Some(self.cmp(other))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use spade::rtree::RTree;
+    use cgmath::Point2;
+    use spatialpoint::SpatialPoint;
+    use graph::contraction::ContractionHierarchy;
+    use graph::charging::{ChargingStation, ChargingCurve};
+
+    use super::*;
+
+    /// A linear congruential generator, which is good enough for reproducible random graphs.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+
+        /// Returns a number between 0 (inclusive) and `n` (exclusive).
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        /// Returns a number between 0 and 1.
+        fn unit(&mut self) -> f64 {
+            self.next() as f64 / (1u64 << 31) as f64
+        }
+    }
+
+    fn weighting() -> Weighting {
+        Weighting { mode: 0, metric: Metric::Duration, vehicle: None }
+    }
+
+    /// Builds a graph having a single transport mode from the longitudes and latitudes of its
+    /// nodes and the source, target and costs of its edges. Edges are straight and flat.
+    fn graph(nodes: &[(f64, f64)], edges: &[(usize, usize, Option<Costs>)]) -> Graph {
+        let nodes = nodes.iter().enumerate()
+            .map(|(i, &(lon, lat))| Node { id: 1000 + i as i64, lon: lon, lat: lat, elevation: 0.0 })
+            .collect::<Vec<_>>();
+        let edges = edges.iter()
+            .map(|&(source, target, costs)| Edge {
+                source: source as i64,
+                target: target as i64,
+                costs: vec![costs],
+                length: nodes[source].dist_to(&nodes[target]) as f32,
+                slope: 0.0,
+                geometry: Vec::new(),
+                way: 0,
+                highway_tag: String::new(),
+            })
+            .collect();
+        let mut rtree = RTree::new();
+        for (i, n) in nodes.iter().enumerate() {
+            rtree.insert(SpatialPoint::new(Point2::new(n.lon, n.lat), i));
+        }
+
+        Graph::new(nodes, edges, rtree, vec![String::from("default")])
+    }
+
+    /// Builds a random graph whose edge weights are at least the Haversine distance between
+    /// their nodes (so that the A* heuristic has something to work with).
+    fn random_graph(rng: &mut Lcg, nodes_count: usize, edges_count: usize) -> Graph {
+        let nodes = (0..nodes_count)
+            .map(|_| (8.5 + rng.unit() * 0.1, 47.3 + rng.unit() * 0.1))
+            .collect::<Vec<_>>();
+        let mut edges = Vec::new();
+        while edges.len() < edges_count {
+            let source = rng.below(nodes_count);
+            let target = rng.below(nodes_count);
+            if source == target {
+                continue;
+            }
+            let (lon1, lat1) = nodes[source];
+            let (lon2, lat2) = nodes[target];
+            let length = Node { id: 0, lon: lon1, lat: lat1, elevation: 0.0 }
+                .dist_to(&Node { id: 0, lon: lon2, lat: lat2, elevation: 0.0 });
+            let weight = (length * (1.0 + rng.unit() * 3.0)) as f32;
+            edges.push((source, target, Some(Costs::uniform(weight))));
+        }
+
+        graph(&nodes, &edges)
+    }
+
+    /// A diamond from node 0 to node 3, whose upper path (via node 1) has fewer edges, but
+    /// higher costs than the lower one (via nodes 2 and 4). Node 5 can only be reached by an
+    /// edge the transport mode cannot use.
+    fn diamond() -> Graph {
+        let nodes = [(8.50, 47.40), (8.51, 47.41), (8.51, 47.39), (8.53, 47.40), (8.52, 47.39), (8.54, 47.40)];
+        let edges = [
+            (0, 1, Some(Costs::uniform(5.0))),
+            (1, 3, Some(Costs::uniform(5.0))),
+            (0, 2, Some(Costs::uniform(1.0))),
+            (2, 4, Some(Costs::uniform(1.0))),
+            (4, 3, Some(Costs::uniform(1.0))),
+            (3, 5, None),
+        ];
+        graph(&nodes, &edges)
+    }
+
+    #[test]
+    fn djikstra_prefers_cheaper_path() {
+        let graph = diamond();
+        let path = graph.djikstra(&[(0, 0.0)], &[(3, 0.0)], weighting()).unwrap();
+        assert_eq!(path.nodes, vec![0, 2, 4, 3]);
+        assert_eq!(path.edges, vec![2, 3, 4]);
+        assert_eq!(path.costs, vec![0.0, 1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn djikstra_without_path() {
+        let graph = diamond();
+        assert!(graph.djikstra(&[(0, 0.0)], &[(5, 0.0)], weighting()).is_none());
+        assert!(graph.djikstra(&[(3, 0.0)], &[(0, 0.0)], weighting()).is_none());
+    }
+
+    #[test]
+    fn djikstra_with_start_and_target_costs() {
+        let graph = diamond();
+        // Reaching node 3 from node 1 costs 0.5 + 5 + 2, from node 0 costs 4 + 3 + 2, whereas
+        // reaching node 4 from node 0 costs 4 + 2 + 0.
+        let path = graph.djikstra(&[(0, 4.0), (1, 0.5)], &[(3, 2.0), (4, 0.0)], weighting()).unwrap();
+        assert_eq!(path.nodes, vec![0, 2, 4]);
+        assert_eq!(path.edges, vec![2, 3]);
+        assert_eq!(path.costs, vec![4.0, 5.0, 6.0]);
+
+        let path = graph.djikstra(&[(0, 4.0), (1, 0.5)], &[(3, 2.0), (4, 2.0)], weighting()).unwrap();
+        assert_eq!(path.nodes, vec![1, 3]);
+        assert_eq!(path.edges, vec![1]);
+        assert_eq!(path.costs, vec![0.5, 5.5]);
+    }
+
+    #[test]
+    fn backtrack_follows_predecessors() {
+        let graph = diamond();
+        let pred = vec![None, Some(0), Some(2), Some(4), Some(3), None];
+        let dist = vec![1.5, 6.5, 2.5, 4.5, 3.5, std::f32::MAX];
+        let path = graph.backtrack(3, &pred, &dist, weighting()).unwrap();
+        assert_eq!(path.nodes, vec![0, 2, 4, 3]);
+        assert_eq!(path.edges, vec![2, 3, 4]);
+        assert_eq!(path.costs, vec![1.5, 2.5, 3.5, 4.5]);
+    }
+
+    #[test]
+    fn backtrack_detects_cycles() {
+        // Predecessors forming a cycle do not lead back to a source.
+        let cycle = graph(&[(8.50, 47.40), (8.51, 47.41)],
+                          &[(0, 1, Some(Costs::uniform(1.0))), (1, 0, Some(Costs::uniform(1.0)))]);
+        assert!(cycle.backtrack(1, &vec![Some(1), Some(0)], &vec![0.0, 0.0], weighting()).is_none());
+    }
+
+    #[test]
+    fn path_from_edges_accumulates_costs() {
+        let graph = diamond();
+        let path = graph.path_from_edges(1, 2.0, vec![1], weighting());
+        assert_eq!(path.nodes, vec![1, 3]);
+        assert_eq!(path.edges, vec![1]);
+        assert_eq!(path.costs, vec![2.0, 7.0]);
+
+        let path = graph.path_from_edges(2, 0.0, Vec::new(), weighting());
+        assert_eq!(path.nodes, vec![2]);
+        assert!(path.edges.is_empty());
+        assert_eq!(path.costs, vec![0.0]);
+    }
+
+    #[test]
+    fn contraction_hierarchy_matches_djikstra() {
+        let mut rng = Lcg(7);
+        for _ in 0..3 {
+            let graph = random_graph(&mut rng, 80, 320);
+            let ch = ContractionHierarchy::build(graph.nodes.len(), &graph.edges, weighting()).unwrap();
+            let hierarchy = Hierarchy::new(&ch, &graph.edges, weighting());
+            for _ in 0..100 {
+                let source = rng.below(80);
+                let target = rng.below(80);
+                let expected = graph.djikstra(&[(source, 0.0)], &[(target, 0.0)], weighting());
+                let actual = hierarchy.query(&[(source, 0.0)], &[(target, 0.0)])
+                    .map(|(start, start_cost, edges)| graph.path_from_edges(start, start_cost, edges, weighting()));
+                match (expected, actual) {
+                    (Some(expected), Some(actual)) => {
+                        // The shortcuts are unpacked into a path of original edges.
+                        for (i, &e) in actual.edges.iter().enumerate() {
+                            assert_eq!(graph.edges[e].source as usize, actual.nodes[i]);
+                        }
+                        assert_eq!(actual.nodes, expected.nodes, "{} -> {}", source, target);
+                        let expected = expected.costs[expected.costs.len() - 1];
+                        let actual = actual.costs[actual.costs.len() - 1];
+                        assert!((expected - actual).abs() <= 1e-4 * expected.max(1.0),
+                                "{} -> {}: expected {}, got {}", source, target, expected, actual);
+                    },
+                    (None, None) => {},
+                    (expected, actual) => panic!("{} -> {}: expected {:?}, got {:?}", source, target, expected, actual)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn route_with_negative_weights() {
+        // The detour via node 1 recuperates energy, so that it needs less than the direct edge.
+        let costs = |duration, energy| Some(Costs { distance: 1.0, duration: duration, energy: energy });
+        let graph = graph(&[(8.50, 47.40), (8.51, 47.41), (8.52, 47.40)],
+                          &[(0, 1, costs(2.0, 1.0)), (1, 2, costs(2.0, -0.8)), (0, 2, costs(3.0, 0.5))]);
+        let weighting = Weighting { mode: 0, metric: Metric::Energy, vehicle: None };
+        let (source, target) = (graph.virtual_node(0), graph.virtual_node(2));
+        assert_eq!(graph.min_costs[0], Costs { distance: 0.0, duration: 0.0, energy: -0.8 });
+        assert!(graph.has_negative_weights(weighting));
+        assert!(!graph.has_negative_weights(Weighting { metric: Metric::Duration, ..weighting }));
+
+        let (trace, costs) = graph.route(&source, &target, RoutingAlgorithm::Djikstra, weighting, true).unwrap();
+        assert!(!trace.is_empty());
+        assert!((costs.energy - 0.2).abs() < 1e-5);
+        assert_eq!(costs.duration, 4.0);
+        for &algorithm in &[RoutingAlgorithm::BidirectionalDjikstra, RoutingAlgorithm::AStar,
+                            RoutingAlgorithm::ContractionHierarchies, RoutingAlgorithm::TurnAwareDjikstra] {
+            assert_eq!(graph.route(&source, &target, algorithm, weighting, true),
+                       Err(RoutingError::NegativeWeights(algorithm)));
+        }
+    }
+
+    /// A vehicle profile, which recuperates energy if `recuperation_efficiency` is positive.
+    fn vehicle(recuperation_efficiency: f64) -> VehicleProfile {
+        VehicleProfile {
+            mass: 1500.0,
+            drag_coefficient: 0.3,
+            frontal_area: 2.2,
+            rolling_resistance: 0.01,
+            drivetrain_efficiency: 0.9,
+            recuperation_efficiency: recuperation_efficiency,
+            auxiliary_load: 0.0,
+        }
+    }
+
+    /// Compares the costs of the paths found by A* to the ones found by Djikstra between random
+    /// sources and targets.
+    fn assert_a_star_optimal(rng: &mut Lcg, graph: &Graph, weighting: Weighting) {
+        let nodes_count = graph.nodes.len();
+        for _ in 0..100 {
+            let source = rng.below(nodes_count);
+            let target = rng.below(nodes_count);
+            let expected = graph.djikstra(&[(source, 0.0)], &[(target, 0.0)], weighting);
+            let actual = graph.a_star(&[(source, 0.0)], &[(target, 0.0)], &graph.nodes[target], weighting);
+            match (expected, actual) {
+                (Some(expected), Some(actual)) => {
+                    let expected = expected.costs[expected.costs.len() - 1];
+                    let actual = actual.costs[actual.costs.len() - 1];
+                    assert!((expected - actual).abs() <= 1e-4 * expected.max(1.0),
+                            "{} -> {}: expected {}, got {}", source, target, expected, actual);
+                },
+                (None, None) => {},
+                (expected, actual) => panic!("{} -> {}: expected {:?}, got {:?}", source, target, expected, actual)
+            }
+        }
+    }
+
+    #[test]
+    fn a_star_finds_shortest_paths() {
+        let mut rng = Lcg(42);
+        for _ in 0..5 {
+            let graph = random_graph(&mut rng, 80, 320);
+            assert_a_star_optimal(&mut rng, &graph, weighting());
+        }
+    }
+
+    #[test]
+    fn a_star_with_vehicle_energy() {
+        let mut rng = Lcg(7);
+        let weighting = Weighting { mode: 0, metric: Metric::Energy, vehicle: Some(vehicle(0.0)) };
+        for _ in 0..5 {
+            let graph = random_graph(&mut rng, 80, 320);
+            assert!(graph.lower_bound_per_km(weighting) > 0.0);
+            assert_a_star_optimal(&mut rng, &graph, weighting);
+        }
+    }
+
+    #[test]
+    fn route_vehicle_energy_downhill() {
+        // Going downhill from node 0 to node 1, a vehicle recuperating energy gains some.
+        let costs = Some(Costs { distance: 1.0, duration: 60.0, energy: 0.1 });
+        let mut graph = graph(&[(8.50, 47.40), (8.51, 47.40), (8.52, 47.40)],
+                              &[(0, 1, costs), (1, 2, costs), (0, 2, costs)]);
+        graph.edges[0].slope = -0.1;
+        let graph = Graph::new(graph.nodes, graph.edges, graph.rtree, graph.modes);
+        let (source, target) = (graph.virtual_node(0), graph.virtual_node(2));
+
+        let recuperating = Weighting { mode: 0, metric: Metric::Energy, vehicle: Some(vehicle(0.6)) };
+        assert!(graph.has_negative_weights(recuperating));
+        assert!(graph.route(&source, &target, RoutingAlgorithm::Djikstra, recuperating, true).is_ok());
+        for &algorithm in &[RoutingAlgorithm::BidirectionalDjikstra, RoutingAlgorithm::AStar,
+                            RoutingAlgorithm::ContractionHierarchies] {
+            assert_eq!(graph.route(&source, &target, algorithm, recuperating, true),
+                       Err(RoutingError::NegativeWeights(algorithm)));
+        }
+
+        let braking = Weighting { mode: 0, metric: Metric::Energy, vehicle: Some(vehicle(0.0)) };
+        assert!(!graph.has_negative_weights(braking));
+        let expected = graph.route(&source, &target, RoutingAlgorithm::Djikstra, braking, false).unwrap().1;
+        for &algorithm in &[RoutingAlgorithm::BidirectionalDjikstra, RoutingAlgorithm::AStar,
+                            RoutingAlgorithm::ContractionHierarchies] {
+            assert_eq!(graph.route(&source, &target, algorithm, braking, false).unwrap().1, expected);
+        }
+    }
+
+    #[test]
+    fn reachability_with_negative_energy_cycle() {
+        // Going back and forth between nodes 0 and 1 recuperates more energy than it needs, which
+        // cannot charge the battery beyond its capacity.
+        let costs = |energy| Some(Costs { distance: 1.0, duration: 60.0, energy: energy });
+        let graph = graph(&[(8.50, 47.40), (8.51, 47.40), (8.52, 47.40)],
+                          &[(0, 1, costs(1.0)), (1, 0, costs(-1.5)), (1, 2, costs(2.5))]);
+        let weighting = Weighting { mode: 0, metric: Metric::Energy, vehicle: None };
+        assert!(graph.has_negative_weights(weighting));
+
+        let (reachable, algorithm) = graph.reachability(0, 2.0, weighting).unwrap();
+        assert_eq!(algorithm, ReachabilityAlgorithm::BellmanFord);
+        let remaining = reachable.iter().map(|loc| loc[2]).collect::<Vec<_>>();
+        assert_eq!(remaining, vec![2.0, 1.0]);
+
+        let duration = Weighting { metric: Metric::Duration, ..weighting };
+        let (reachable, algorithm) = graph.reachability(0, 150.0, duration).unwrap();
+        assert_eq!(algorithm, ReachabilityAlgorithm::Djikstra);
+        assert_eq!(reachable.len(), 3);
+    }
+
+    #[test]
+    fn bellman_detects_negative_cycle() {
+        // Going from node 1 to node 2 and back costs -1.5.
+        let cycle = graph(&[(8.50, 47.40), (8.51, 47.40), (8.52, 47.40), (8.53, 47.40)],
+                          &[(0, 1, Some(Costs::uniform(1.0))), (1, 2, Some(Costs::uniform(-2.0))),
+                            (2, 1, Some(Costs::uniform(0.5))), (2, 3, Some(Costs::uniform(1.0)))]);
+        match cycle.bellman(&[(0, 0.0)], weighting()) {
+            Err(RoutingError::NegativeCycle(mut nodes, mut edges)) => {
+                nodes.sort();
+                edges.sort();
+                assert_eq!(nodes, vec![1001, 1002]);
+                assert_eq!(edges, vec![1, 2]);
+            },
+            res => panic!("expected a negative cycle, got {:?}", res)
+        }
+        assert!(cycle.validate(weighting()).is_err());
+
+        // The cycle cannot be reached from node 3.
+        let (_, dist) = cycle.bellman(&[(3, 0.0)], weighting()).unwrap();
+        assert_eq!(dist, vec![std::f32::MAX, std::f32::MAX, std::f32::MAX, 0.0]);
+    }
+
+    #[test]
+    fn bellman_matches_djikstra_on_shifted_weights() {
+        // Shifting the weight of every edge by the difference of the potentials of its nodes
+        // changes the costs of all paths from a source to a target by the same amount, and keeps
+        // the costs of all cycles. So the energy has negative edge weights, but no negative cycle.
+        let mut rng = Lcg(21);
+        let base = random_graph(&mut rng, 60, 240);
+        let potentials = (0..60).map(|_| rng.unit() as f32 * 2.0).collect::<Vec<_>>();
+        let nodes = base.nodes.iter().map(|n| (n.lon, n.lat)).collect::<Vec<_>>();
+        let edges = base.edges.iter()
+            .map(|e| {
+                let (source, target) = (e.source as usize, e.target as usize);
+                let costs = e.costs[0].unwrap();
+                let energy = costs.duration + potentials[source] - potentials[target];
+                (source, target, Some(Costs { energy: energy, ..costs }))
+            })
+            .collect::<Vec<_>>();
+        let shifted = graph(&nodes, &edges);
+        let energy = Weighting { mode: 0, metric: Metric::Energy, vehicle: None };
+        assert!(shifted.has_negative_weights(energy));
+
+        for source in 0..10 {
+            let (_, dist) = shifted.bellman(&[(source, 0.0)], energy).unwrap();
+            for target in 0..60 {
+                match shifted.djikstra(&[(source, 0.0)], &[(target, 0.0)], weighting()) {
+                    Some(path) => {
+                        let expected = path.costs[path.costs.len() - 1] + potentials[source] - potentials[target];
+                        assert!((dist[target] - expected).abs() <= 1e-4 * expected.abs().max(1.0),
+                                "{} -> {}: expected {}, got {}", source, target, expected, dist[target]);
+                    },
+                    None => assert_eq!(dist[target], std::f32::MAX)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn charging_route_charges_just_enough() {
+        // A road from node 0 to node 4 needing 2.5 kWh per edge, with a charging station at node
+        // 2. The battery holds 8 kWh, so the vehicle has to charge there, and charging exactly the
+        // 5 kWh needed for the rest of the way (not a multiple of 1% of the capacity) is fastest.
+        let nodes = [(8.50, 47.40), (8.51, 47.40), (8.52, 47.40), (8.53, 47.40), (8.54, 47.40)];
+        let costs = Some(Costs { distance: 1.0, duration: 60.0, energy: 2.5 });
+        let edges = (0..4).flat_map(|i| vec![(i, i + 1, costs), (i + 1, i, costs)]).collect::<Vec<_>>();
+        let mut graph = graph(&nodes, &edges);
+        graph.charging_stations = vec![ChargingStation {
+            id: 1,
+            lon: 8.52,
+            lat: 47.40,
+            capacity: None,
+            sockets: Vec::new(),
+            power: None,
+        }];
+        graph.link_charging_stations();
+
+        let curve = ChargingCurve::parse("0:50,100:50").unwrap();
+        let (trace, costs, stops, remaining) = graph.charging_route(0, 4, weighting(), 8.0, 5.0, &curve)
+            .unwrap();
+        assert_eq!(trace.len(), 5);
+        assert_eq!(trace[4], vec![8.54, 47.40]);
+        assert_eq!(costs.duration, 240.0);
+        assert_eq!(costs.energy, 10.0);
+        assert_eq!(stops.len(), 1);
+        assert_eq!(stops[0].station, 0);
+        assert_eq!(stops[0].arrival_charge, 0.0);
+        assert_eq!(stops[0].departure_charge, 5.0);
+        // Charging 5 kWh at 50 kW takes 6 minutes.
+        assert!((stops[0].duration - 360.0).abs() < 1e-2);
+        assert_eq!(remaining, 0.0);
+
+        // Without the charging station, the target cannot be reached.
+        graph.charging_stations = Vec::new();
+        graph.link_charging_stations();
+        let (trace, _, stops, _) = graph.charging_route(0, 4, weighting(), 8.0, 5.0, &curve).unwrap();
+        assert!(trace.is_empty());
+        assert!(stops.is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use std;
+use std::collections::{HashMap, HashSet};
+use spade::delaunay::FloatDelaunayTriangulation;
+use cgmath::Point2;
+
+use graph::core::{Graph, Weighting, ReachabilityAlgorithm, RoutingError};
+
+/// The length of a degree of latitude in kilometers.
+const KM_PER_DEGREE_LAT: f64 = 110.574;
+
+/// The length of a degree of longitude at the equator in kilometers.
+const KM_PER_DEGREE_LON: f64 = 111.32;
+
+/// A polygon, given as its outer ring followed by its holes. Every ring is a closed list of
+/// longitude and latitude pairs, where the outer ring is counterclockwise and holes are
+/// clockwise.
+pub type Polygon = Vec<Vec<Vec<f64>>>;
+
+/// Implementation of isochrones.
+impl Graph {
+    /// Computes the isochrones around a source (given as internal id) for a weighting, i.e., for
+    /// every threshold (in percent of the capacity), the area that can be reached using at most
+    /// that share of the capacity. The areas are the alpha shapes of the reachable nodes, where
+    /// `alpha` (in kilometers) is the largest circumradius of a triangle that is part of an area.
+    /// Returns the polygons for every threshold, together with the algorithm used to compute the
+    /// reachable nodes.
+    pub fn isochrones(&self, source_id: usize, capacity: f32, weighting: Weighting, thresholds: &[f32],
+                      alpha: f64) -> Result<(Vec<(f32, Vec<Polygon>)>, ReachabilityAlgorithm), RoutingError> {
+        let (reachable, algorithm) = match self.reachability(source_id, capacity, weighting) {
+            Ok(reachable) => reachable,
+            Err(err) => return Err(err)
+        };
+
+        let mut isochrones = Vec::new();
+        for &threshold in thresholds {
+            let remaining = (capacity * (1.0 - threshold / 100.0)) as f64;
+            let points = reachable.iter()
+                .filter(|loc| loc[2] >= remaining)
+                .cloned()
+                .collect::<Vec<_>>();
+            let polygons = concave_hull(&points, alpha);
+            println!(" ˪— {} nodes within {}% of the capacity, forming {} polygons.",
+                     points.len(), threshold, polygons.len());
+            isochrones.push((threshold, polygons));
+        }
+
+        Ok((isochrones, algorithm))
+    }
+}
+
+/// Computes the alpha shape of a set of points (given as longitude and latitude), which consists
+/// of all triangles of their Delaunay triangulation whose circumradius is at most `alpha` (in
+/// kilometers). Returns the polygons forming the boundary of the alpha shape.
+pub fn concave_hull(points: &[Vec<f64>], alpha: f64) -> Vec<Polygon> {
+    if points.len() < 3 {
+        return Vec::new();
+    }
+
+    // The points are projected to kilometers around their mean latitude, so that the
+    // circumradius can be compared to alpha.
+    let mean_lat = points.iter().map(|p| p[1]).sum::<f64>() / points.len() as f64;
+    let km_per_degree_lon = KM_PER_DEGREE_LON * mean_lat.to_radians().cos();
+    let mut delaunay = FloatDelaunayTriangulation::with_walk_locate();
+    for p in points {
+        delaunay.insert(Point2::new(p[0] * km_per_degree_lon, p[1] * KM_PER_DEGREE_LAT));
+    }
+
+    // Collect the edges of all triangles of the alpha shape, in counterclockwise order.
+    let mut positions = HashMap::new();
+    let mut edges = Vec::new();
+    for face in delaunay.triangles() {
+        let triangle = face.as_triangle();
+        let mut vertices = [(triangle[0].fix(), *triangle[0]), (triangle[1].fix(), *triangle[1]),
+                            (triangle[2].fix(), *triangle[2])];
+        if circumradius(vertices[0].1, vertices[1].1, vertices[2].1) > alpha {
+            continue;
+        }
+        if cross(vertices[0].1, vertices[1].1, vertices[2].1) < 0.0 {
+            vertices.swap(1, 2);
+        }
+        for i in 0..3 {
+            positions.insert(vertices[i].0, vertices[i].1);
+            edges.push((vertices[i].0, vertices[(i + 1) % 3].0));
+        }
+    }
+
+    // An edge lies on the boundary if the neighbouring triangle is not part of the alpha shape,
+    // i.e., the edge in the opposite direction is not part of it either.
+    let edge_set = edges.iter().cloned().collect::<HashSet<_>>();
+    let mut next: HashMap<usize, Vec<usize>> = HashMap::new();
+    for &(from, to) in &edges {
+        if !edge_set.contains(&(to, from)) {
+            next.entry(from).or_insert_with(Vec::new).push(to);
+        }
+    }
+
+    // Chain the boundary edges to rings. As the triangles are counterclockwise, outer rings are
+    // counterclockwise and holes are clockwise.
+    let mut outer_rings = Vec::new();
+    let mut holes = Vec::new();
+    let starts = next.keys().cloned().collect::<Vec<_>>();
+    for start in starts {
+        while let Some(first) = next.get_mut(&start).and_then(|n| n.pop()) {
+            let mut ring = vec![positions[&start]];
+            let mut current = first;
+            while current != start {
+                ring.push(positions[&current]);
+                match next.get_mut(&current).and_then(|n| n.pop()) {
+                    Some(n) => current = n,
+                    None => break
+                }
+            }
+            if ring.len() < 3 {
+                continue;
+            }
+            if ring_area(&ring) > 0.0 {
+                outer_rings.push(ring);
+            } else {
+                holes.push(ring);
+            }
+        }
+    }
+
+    // Every hole belongs to the smallest outer ring containing it.
+    let mut polygons = outer_rings.into_iter().map(|ring| vec![ring]).collect::<Vec<_>>();
+    for hole in holes {
+        let outer = polygons.iter().enumerate()
+            .filter(|&(_, polygon)| ring_contains(&polygon[0], hole[0]))
+            .min_by(|a, b| ring_area(&a.1[0]).partial_cmp(&ring_area(&b.1[0]))
+                .unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(i, _)| i);
+        if let Some(outer) = outer {
+            polygons[outer].push(hole);
+        }
+    }
+
+    // Project the rings back to longitude and latitude, and close them.
+    polygons.iter()
+        .map(|polygon| polygon.iter()
+            .map(|ring| ring.iter()
+                .chain(ring.first())
+                .map(|p| vec![p.x / km_per_degree_lon, p.y / KM_PER_DEGREE_LAT])
+                .collect())
+            .collect())
+        .collect()
+}
+
+/// Computes the cross product of the vectors from `a` to `b` and from `a` to `c`, which is
+/// positive if the triangle is counterclockwise.
+fn cross(a: Point2<f64>, b: Point2<f64>, c: Point2<f64>) -> f64 {
+    (b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y)
+}
+
+/// Computes the radius of the circle through the corners of a triangle.
+fn circumradius(a: Point2<f64>, b: Point2<f64>, c: Point2<f64>) -> f64 {
+    let area = cross(a, b, c).abs() / 2.0;
+    if area == 0.0 {
+        return std::f64::MAX;
+    }
+    let length = |p: Point2<f64>, q: Point2<f64>| ((p.x - q.x).powi(2) + (p.y - q.y).powi(2)).sqrt();
+    length(a, b) * length(b, c) * length(c, a) / (4.0 * area)
+}
+
+/// Computes the signed area of a ring, which is positive if it is counterclockwise.
+fn ring_area(ring: &[Point2<f64>]) -> f64 {
+    let mut area = 0.0;
+    for i in 0..ring.len() {
+        let (p, q) = (ring[i], ring[(i + 1) % ring.len()]);
+        area += p.x * q.y - q.x * p.y;
+    }
+    area / 2.0
+}
+
+/// Checks whether a point lies within a ring, by counting how often a ray starting at the
+/// point crosses the ring.
+fn ring_contains(ring: &[Point2<f64>], point: Point2<f64>) -> bool {
+    let mut inside = false;
+    for i in 0..ring.len() {
+        let (p, q) = (ring[i], ring[(i + 1) % ring.len()]);
+        if (p.y > point.y) != (q.y > point.y) &&
+            point.x < p.x + (point.y - p.y) / (q.y - p.y) * (q.x - p.x) {
+            inside = !inside;
+        }
+    }
+    inside
+}
@@ -1,3 +1,9 @@
 pub mod core;
 pub mod builder;
-pub mod serializer;
\ No newline at end of file
+pub mod serializer;
+pub mod contraction;
+pub mod charging;
+pub mod vehicle;
+pub mod isochrone;
+pub mod components;
+pub mod restriction;
\ No newline at end of file
@@ -0,0 +1,290 @@
+use std::collections::{HashMap, VecDeque};
+
+use graph::core::{Node, Edge, build_adjacency};
+
+/// The maximal number of edges of a restricted sequence, which limits the search for the edges
+/// along the via ways of a restriction.
+const MAX_RESTRICTION_EDGES: usize = 16;
+
+/// A turn restriction as tagged in OSM, given by the OSM ids of its members.
+#[derive(Debug, Clone)]
+pub struct OsmRestriction {
+    /// The ways the restriction starts from (usually one, several for `no_entry`).
+    pub from: Vec<i64>,
+    /// The node at which the turn takes place, if the restriction has a via node.
+    pub via_node: Option<i64>,
+    /// The ways between the from and the to ways, in order, if the restriction has via ways.
+    pub via_ways: Vec<i64>,
+    /// The ways the restriction leads to (usually one, several for `no_exit`).
+    pub to: Vec<i64>,
+    /// Whether this is an `only_*` restriction, i.e., the to way is the only one that may be
+    /// taken. Otherwise, it is a `no_*` restriction, which forbids taking the to way.
+    pub only: bool,
+    /// The indices of the transport modes the restriction applies to.
+    pub modes: Vec<usize>,
+}
+
+/// A sequence of edges that must not be traversed in this order by some transport modes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TurnRestriction {
+    /// The indices of the edges, in the order they must not be traversed.
+    pub edges: Vec<usize>,
+    /// The indices of the transport modes the restriction applies to.
+    pub modes: Vec<usize>,
+}
+
+/// A trie of all restricted sequences of edges, which tells whether a turn from one edge onto
+/// another completes a restricted sequence. A position in the trie stands for the longest
+/// beginning of a restricted sequence matched by the edges traversed last, where position 0 (the
+/// root) means that nothing is matched. As in the Aho-Corasick algorithm, failure links lead to
+/// the next shorter beginning matched, so that overlapping sequences are matched as well.
+#[derive(Debug)]
+pub struct RestrictionTrie {
+    /// The children of every position, by the next edge of the sequence.
+    children: Vec<HashMap<usize, usize>>,
+    /// The position of the longest proper suffix of the sequence of every position that is the
+    /// beginning of a restricted sequence as well.
+    fail: Vec<usize>,
+    /// The transport modes for which the sequence of every position, or one of its suffixes, is
+    /// restricted.
+    modes: Vec<Vec<usize>>,
+}
+
+impl RestrictionTrie {
+    /// Builds the trie of the given turn restrictions.
+    pub fn new(restrictions: &[TurnRestriction]) -> RestrictionTrie {
+        let mut trie = RestrictionTrie {
+            children: vec![HashMap::new()],
+            fail: Vec::new(),
+            modes: vec![Vec::new()],
+        };
+        for restriction in restrictions {
+            let mut position = 0;
+            for &edge in &restriction.edges {
+                let existing = trie.children[position].get(&edge).cloned();
+                position = match existing {
+                    Some(child) => child,
+                    None => {
+                        let child = trie.children.len();
+                        trie.children.push(HashMap::new());
+                        trie.modes.push(Vec::new());
+                        trie.children[position].insert(edge, child);
+                        child
+                    }
+                };
+            }
+            trie.modes[position].extend(restriction.modes.iter().cloned());
+        }
+
+        // The failure links are found breadth-first, as they lead to shorter sequences. Every
+        // position inherits the restricted modes of its failure link, which ends with the same
+        // edges.
+        trie.fail = vec![0; trie.children.len()];
+        let mut queue = trie.children[0].values().cloned().collect::<VecDeque<_>>();
+        while let Some(position) = queue.pop_front() {
+            let children = trie.children[position].iter().map(|(&e, &c)| (e, c)).collect::<Vec<_>>();
+            for (edge, child) in children {
+                let fail = trie.next(trie.fail[position], edge);
+                trie.fail[child] = fail;
+                let inherited = trie.modes[fail].clone();
+                trie.modes[child].extend(inherited);
+                trie.modes[child].sort();
+                trie.modes[child].dedup();
+                queue.push_back(child);
+            }
+        }
+
+        trie
+    }
+
+    /// Checks whether the trie contains no restrictions.
+    pub fn is_empty(&self) -> bool {
+        self.children.len() == 1
+    }
+
+    /// Returns the position after traversing the first edge of a route.
+    pub fn start(&self, edge: usize) -> usize {
+        self.next(0, edge)
+    }
+
+    /// Returns the position after turning onto an edge, given the position reached with the
+    /// edges traversed before, or `None` if the turn completes a restricted sequence for the
+    /// transport mode.
+    pub fn turn(&self, position: usize, to: usize, mode: usize) -> Option<usize> {
+        let next = self.next(position, to);
+        if self.modes[next].contains(&mode) {
+            None
+        } else {
+            Some(next)
+        }
+    }
+
+    /// Returns the position after traversing an edge from a position, following the failure
+    /// links until the edge continues the sequence of a position (or the root is reached).
+    fn next(&self, position: usize, edge: usize) -> usize {
+        let mut current = position;
+        loop {
+            if let Some(&child) = self.children[current].get(&edge) {
+                return child;
+            }
+            if current == 0 {
+                return 0;
+            }
+            current = self.fail[current];
+        }
+    }
+}
+
+/// Resolves turn restrictions given by OSM ids to the edges of the graph. A restriction covers
+/// all sequences of edges that start with an edge of its from way, then turn at its via node (or
+/// follow its via ways), and end with an edge of its to way. A `no_*` restriction forbids these
+/// sequences, while an `only_*` restriction forbids all other turns at the end of them.
+/// Restrictions whose members are not part of the graph are skipped.
+pub fn resolve_restrictions(restrictions: &[OsmRestriction], nodes: &[Node], edges: &[Edge])
+                            -> Vec<TurnRestriction> {
+    let mut way_edges: HashMap<i64, Vec<usize>> = HashMap::new();
+    for (i, edge) in edges.iter().enumerate() {
+        way_edges.entry(edge.way).or_insert_with(Vec::new).push(i);
+    }
+    let node_index: HashMap<i64, usize> = nodes.iter().enumerate().map(|(i, n)| (n.id, i)).collect();
+    let (offsets, out_edges) = build_adjacency(nodes.len(), edges.iter().map(|e| e.source as usize));
+
+    let mut resolved = Vec::new();
+    for restriction in restrictions {
+        let via_node = match restriction.via_node {
+            Some(id) => match node_index.get(&id) {
+                Some(&node) => Some(node),
+                None => continue
+            },
+            None => None
+        };
+
+        for &from in &restriction.from {
+            for &to in &restriction.to {
+                let mut ways = vec![from];
+                ways.extend(restriction.via_ways.iter().cloned());
+                ways.push(to);
+
+                let starts = way_edges.get(&from).map_or(Vec::new(), |starts| starts.iter()
+                    .cloned()
+                    .filter(|&e| via_node.map_or(true, |node| edges[e].target as usize == node))
+                    .collect());
+                for start in starts {
+                    for sequence in way_sequences(start, &ways, edges, &offsets, &out_edges) {
+                        if !restriction.only {
+                            resolved.push(TurnRestriction { edges: sequence, modes: restriction.modes.clone() });
+                            continue;
+                        }
+
+                        // All turns at the end of the sequence that do not lead onto the to way
+                        // are forbidden.
+                        let (last, prefix) = sequence.split_last().unwrap();
+                        let node = edges[*last].source as usize;
+                        for &e in &out_edges[offsets[node]..offsets[node + 1]] {
+                            if edges[e].way != to {
+                                let mut forbidden = prefix.to_vec();
+                                forbidden.push(e);
+                                resolved.push(TurnRestriction { edges: forbidden, modes: restriction.modes.clone() });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    resolved
+}
+
+/// Finds all sequences of edges that start with the given edge (of the first way), and then
+/// follow the ways in the given order, where every way except the first and the last one may
+/// span several edges. U-turns within these ways are not followed.
+fn way_sequences(start: usize, ways: &[i64], edges: &[Edge], offsets: &[usize], out_edges: &[usize])
+                 -> Vec<Vec<usize>> {
+    let mut sequences = Vec::new();
+    // The partial sequences still to be extended, with the index of the way of their last edge.
+    let mut stack = vec![(vec![start], 0)];
+    while let Some((sequence, way)) = stack.pop() {
+        if sequence.len() >= MAX_RESTRICTION_EDGES {
+            continue;
+        }
+
+        let last = &edges[sequence[sequence.len() - 1]];
+        let node = last.target as usize;
+        for &e in &out_edges[offsets[node]..offsets[node + 1]] {
+            let edge = &edges[e];
+            let next = if edge.way == ways[way + 1] {
+                way + 1
+            } else if way > 0 && edge.way == ways[way] && edge.target != last.source {
+                way
+            } else {
+                continue;
+            };
+
+            let mut extended = sequence.clone();
+            extended.push(e);
+            if next == ways.len() - 1 {
+                sequences.push(extended);
+            } else {
+                stack.push((extended, next));
+            }
+        }
+    }
+
+    sequences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(restrictions: Vec<(Vec<usize>, Vec<usize>)>) -> RestrictionTrie {
+        RestrictionTrie::new(&restrictions.into_iter()
+            .map(|(edges, modes)| TurnRestriction { edges: edges, modes: modes })
+            .collect::<Vec<_>>())
+    }
+
+    /// Follows the edges of a route, and returns whether it completes a restricted sequence.
+    fn restricted(trie: &RestrictionTrie, edges: &[usize], mode: usize) -> bool {
+        let mut position = trie.start(edges[0]);
+        for &edge in &edges[1..] {
+            match trie.turn(position, edge, mode) {
+                Some(next) => position = next,
+                None => return true
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn simple_restrictions() {
+        let trie = build(vec![(vec![1, 2], vec![0]), (vec![3, 4, 5], vec![0, 1])]);
+        assert!(restricted(&trie, &[1, 2], 0));
+        assert!(!restricted(&trie, &[1, 2], 1));
+        assert!(restricted(&trie, &[6, 1, 2], 0));
+        assert!(restricted(&trie, &[3, 4, 5], 1));
+        assert!(!restricted(&trie, &[3, 4, 6, 5], 1));
+        assert!(!restricted(&trie, &[2, 1, 3, 5], 0));
+    }
+
+    #[test]
+    fn overlapping_restrictions() {
+        // After 1, 2, 3, the sequence 2, 3, 5 is matched as well, although none of its edges
+        // is the first one of the longer sequence.
+        let trie = build(vec![(vec![1, 2, 3, 4], vec![0]), (vec![2, 3, 5], vec![0])]);
+        assert!(restricted(&trie, &[1, 2, 3, 5], 0));
+        assert!(restricted(&trie, &[1, 2, 3, 4], 0));
+        assert!(!restricted(&trie, &[1, 2, 3, 6], 0));
+
+        // The sequence 2, 3 is a suffix of 1, 2, 3, so it is restricted there as well.
+        let trie = build(vec![(vec![1, 2, 3, 4], vec![0]), (vec![2, 3], vec![1])]);
+        assert!(restricted(&trie, &[1, 2, 3], 1));
+        assert!(!restricted(&trie, &[1, 2, 3], 0));
+        assert!(restricted(&trie, &[1, 2, 3, 4], 0));
+
+        // Repeated edges (e.g., going around a block) fall back to shorter beginnings.
+        let trie = build(vec![(vec![1, 1, 2], vec![0])]);
+        assert!(restricted(&trie, &[1, 1, 1, 2], 0));
+        assert!(!restricted(&trie, &[1, 2, 1, 2], 0));
+    }
+}
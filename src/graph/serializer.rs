@@ -10,7 +10,10 @@ use cgmath::Point2;
 use spatialpoint::SpatialPoint;
 use pbr::ProgressBar;
 
-use graph::core::{Graph, Edge, Node};
+use graph::core::{Graph, Edge, Node, Weighting, TurnCost};
+use graph::contraction::{ContractionHierarchy, Hierarchy};
+use graph::charging::ChargingStation;
+use graph::restriction::{TurnRestriction, RestrictionTrie};
 
 /// Contains parts of a graph that can be serialized.
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,7 +21,17 @@ pub struct SerializableGraph {
     /// All the edges contained in the graph.
     pub edges: Vec<Edge>,
     /// All the nodes contained in this graph.
-    pub nodes: Vec<Node>
+    pub nodes: Vec<Node>,
+    /// The names of the transport modes, in the order of the edge costs.
+    pub modes: Vec<String>,
+    /// The contraction hierarchies of all weightings that were preprocessed.
+    pub hierarchies: Vec<(Weighting, ContractionHierarchy)>,
+    /// All charging stations for electric vehicles.
+    pub charging_stations: Vec<ChargingStation>,
+    /// The restricted sequences of edges, resolved from the turn restrictions.
+    pub turn_restrictions: Vec<TurnRestriction>,
+    /// The costs of all turns that have costs for any transport mode.
+    pub turn_costs: Vec<TurnCost>,
 }
 
 impl SerializableGraph {
@@ -39,12 +52,20 @@ impl SerializableGraph {
         let count = self.nodes.len();
         let mut pb = ProgressBar::new(count as u64);
         pb.format("╢▌▌░╟");
-        for n in self.nodes.iter() {
-            let p = SpatialPoint::new(Point2::new(n.lon, n.lat), n.id);
+        for (i, n) in self.nodes.iter().enumerate() {
+            let p = SpatialPoint::new(Point2::new(n.lon, n.lat), i);
             rtree.insert(p);
             pb.inc();
         }
 
-        Graph { edges: self.edges.clone(), nodes: self.nodes.clone(), rtree: rtree }
+        let mut graph = Graph::new(self.nodes.clone(), self.edges.clone(), rtree, self.modes.clone());
+        for &(weighting, ref hierarchy) in &self.hierarchies {
+            let hierarchy = Hierarchy::new(hierarchy, &graph.edges, weighting);
+            graph.hierarchies.push((weighting, hierarchy));
+        }
+        graph.charging_stations = self.charging_stations.clone();
+        graph.turn_restrictions = RestrictionTrie::new(&self.turn_restrictions);
+        graph.turn_costs = self.turn_costs.iter().map(|t| ((t.from, t.to), t.costs.clone())).collect();
+        graph
     }
 }
\ No newline at end of file
@@ -0,0 +1,64 @@
+/// The gravitational acceleration in m/s².
+const GRAVITY: f64 = 9.81;
+
+/// The density of air in kg/m³.
+const AIR_DENSITY: f64 = 1.2;
+
+/// The physical properties of a vehicle, from which the energy it needs along an edge is
+/// computed at query time.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, RustcDecodable)]
+pub struct VehicleProfile {
+    /// The mass of the vehicle (including its load) in kg.
+    pub mass: f64,
+    /// The drag coefficient of the vehicle.
+    pub drag_coefficient: f64,
+    /// The frontal area of the vehicle in m².
+    pub frontal_area: f64,
+    /// The rolling resistance coefficient of the tires.
+    pub rolling_resistance: f64,
+    /// The share of the energy drawn from the battery that moves the vehicle.
+    pub drivetrain_efficiency: f64,
+    /// The share of the energy gained when braking or going downhill that is recuperated.
+    pub recuperation_efficiency: f64,
+    /// The power drawn by auxiliary consumers (e.g., heating or air conditioning) in kW.
+    pub auxiliary_load: f64,
+}
+
+impl VehicleProfile {
+    /// Computes the energy (in kWh) the vehicle draws from its battery along an edge, given its
+    /// length (in kilometers), its slope, and the duration (in seconds) needed to traverse it,
+    /// which determines the speed. This is negative if energy is recuperated.
+    pub fn energy(&self, length: f32, slope: f32, duration: f32) -> f32 {
+        let length = length as f64 * 1000.0;
+        let duration = duration as f64;
+        let speed = if duration > 0.0 { length / duration } else { 0.0 };
+
+        // The work (in J) needed against rolling resistance, gravity and air resistance.
+        let work = self.mass * GRAVITY * (self.rolling_resistance + slope as f64) * length
+            + 0.5 * AIR_DENSITY * self.drag_coefficient * self.frontal_area * speed * speed * length;
+        let battery = if work > 0.0 {
+            work / self.drivetrain_efficiency
+        } else {
+            work * self.recuperation_efficiency
+        };
+
+        (battery / 3600000.0 + self.auxiliary_load * duration / 3600.0) as f32
+    }
+
+    /// Computes a lower bound of the energy (in kWh) the vehicle draws from its battery per
+    /// kilometer of an edge, given the minimal slope and the minimal duration (in seconds) per
+    /// kilometer of all edges. This holds for edges whose energy is not negative, and is reduced
+    /// slightly, so that rounding errors cannot make it overestimate.
+    pub fn min_energy_per_km(&self, min_slope: f32, min_duration_per_km: f32) -> f32 {
+        // If even the steepest descent needs work against rolling resistance and gravity, the
+        // battery provides at least this work (plus the air resistance) along every edge.
+        // Otherwise, an edge may need no energy at all.
+        let work = self.mass * GRAVITY * (self.rolling_resistance + min_slope as f64) * 1000.0;
+        if work <= 0.0 {
+            return 0.0;
+        }
+        let battery = work / self.drivetrain_efficiency.max(1.0);
+
+        ((battery / 3600000.0 + self.auxiliary_load * min_duration_per_km as f64 / 3600.0) * 0.999) as f32
+    }
+}
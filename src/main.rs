@@ -24,6 +24,8 @@ extern crate bincode;
 extern crate flate2;
 extern crate gluon;
 #[macro_use]
+extern crate gluon_codegen;
+#[macro_use]
 extern crate log;
 extern crate log4rs;
 
@@ -40,12 +42,16 @@ use config::*;
 
 mod graph;
 mod spatialpoint;
+mod spatialedge;
+mod elevation;
 mod endpoints;
 
 use graph::builder::GraphBuilder;
 use graph::serializer::SerializableGraph;
-use graph::core::Graph;
+use graph::contraction::ContractionHierarchy;
+use graph::core::{Graph, Weighting, METRICS};
 use endpoints::GraphPool;
+use elevation::ElevationModel;
 use rust_geotiff::TIFF;
 
 /// Main function and entry point to the program.
@@ -67,8 +73,10 @@ fn main() {
 
     match route_app.value_of("mode") {
         Some("build-graph") => build_graph(settings_map),
+        Some("build-ch") => build_ch(settings_map),
+        Some("validate-graph") => validate_graph(settings_map),
         Some("run-server") => run_server(settings_map),
-        _ => panic!("Unknown mode! Use one of 'build-graph', 'run-server'.")
+        _ => panic!("Unknown mode! Use one of 'build-graph', 'build-ch', 'validate-graph', 'run-server'.")
     }
 }
 
@@ -78,6 +86,11 @@ fn build_graph(settings_map: HashMap<String, String>) -> () {
     let dem_file = settings_map.get("dem_file").unwrap();
     info!(target: "graph::builder", "Reading DEM file from '{}'.", dem_file);
     let img = TIFF::open(dem_file).unwrap();
+    let dem = ElevationModel::new(img,
+                                  settings_map.get("dem_origin_lon").unwrap().parse::<f64>().unwrap(),
+                                  settings_map.get("dem_origin_lat").unwrap().parse::<f64>().unwrap(),
+                                  settings_map.get("dem_cell_width").unwrap().parse::<f64>().unwrap(),
+                                  settings_map.get("dem_cell_height").unwrap().parse::<f64>().unwrap());
     info!(target: "graph::builder", "Finished reading DEM file.");
 
     // Loading the OSM pbf data.
@@ -94,16 +107,88 @@ fn build_graph(settings_map: HashMap<String, String>) -> () {
     pb.format("╢▌▌░╟");
     pb.inc();
 
-    let script_file = std::fs::File::open(
-        &std::path::Path::new(settings_map.get("transport_mode").unwrap()));
-
-    let graph = GraphBuilder::build_from_pbf(&mut pbf, &mut script_file.unwrap(), &img);
+    let prelude_file = std::fs::File::open(
+        &std::path::Path::new(settings_map.get("transport_prelude").unwrap()));
+    // The transport modes are given as a comma-separated list of scripts, and named after
+    // their file names (e.g., "car" for "transport-modes/car.glu").
+    let mut script_files = settings_map.get("transport_modes").unwrap()
+        .split(',')
+        .map(|script| {
+            let script_path = std::path::Path::new(script.trim());
+            let name = script_path.file_stem().unwrap().to_string_lossy().into_owned();
+            info!(target: "graph::builder", "Using transport mode '{}' from '{}'.", name, script.trim());
+            (name, std::fs::File::open(&script_path).unwrap())
+        })
+        .collect::<Vec<_>>();
+
+    let graph = GraphBuilder::build_from_pbf(&mut pbf, &mut prelude_file.unwrap(),
+                                             &mut script_files, &dem);
     info!(target: "graph::builder", "Finished building graph, starting to write to file.");    
     let graph_file = settings_map.get("graph_file").unwrap();
     graph.write_to_file(graph_file);
     info!(target: "graph::builder", "Finished graph construction.");
 }
 
+/// Contraction hierarchies preprocessing facility. Adds the shortcuts of every transport mode
+/// and metric to the graph file.
+fn build_ch(settings_map: HashMap<String, String>) -> () {
+    let graph_file = settings_map.get("graph_file").unwrap();
+    info!(target: "graph::builder", "Reading graph from '{}'.", graph_file);
+    let mut graph = SerializableGraph::read_from_file(graph_file);
+
+    graph.hierarchies.clear();
+    for mode in 0..graph.modes.len() {
+        for &metric in METRICS.iter() {
+            let weighting = Weighting { mode: mode, metric: metric, vehicle: None };
+            info!(target: "graph::builder", "Starting contraction of {} nodes for transport mode '{}' ({:?}).",
+                  graph.nodes.len(), graph.modes[mode], metric);
+            match ContractionHierarchy::build(graph.nodes.len(), &graph.edges, weighting) {
+                Some(hierarchy) => {
+                    info!(target: "graph::builder", "Finished contraction, added {} shortcuts.",
+                          hierarchy.shortcuts.len());
+                    graph.hierarchies.push((weighting, hierarchy));
+                },
+                None => warn!(target: "graph::builder",
+                              "Transport mode '{}' has negative edge weights ({:?}) and cannot be contracted.",
+                              graph.modes[mode], metric)
+            }
+        }
+    }
+
+    info!(target: "graph::builder", "Writing to file.");
+    graph.write_to_file(graph_file);
+    info!(target: "graph::builder", "Finished contraction hierarchies preprocessing.");
+}
+
+/// Graph validation facility. Checks the edge weights of every transport mode and metric for
+/// negative cycles, and exits with an error if there are any.
+fn validate_graph(settings_map: HashMap<String, String>) -> () {
+    let graph_file = settings_map.get("graph_file").unwrap();
+    info!(target: "graph::validator", "Reading graph from '{}'.", graph_file);
+    let graph = SerializableGraph::read_from_file(graph_file).to_graph();
+
+    let mut valid = true;
+    for mode in 0..graph.modes.len() {
+        for &metric in METRICS.iter() {
+            let weighting = Weighting { mode: mode, metric: metric, vehicle: None };
+            match graph.validate(weighting) {
+                Ok(()) => info!(target: "graph::validator", "Transport mode '{}' ({:?}) is valid.",
+                                graph.modes[mode], metric),
+                Err(err) => {
+                    error!(target: "graph::validator", "Transport mode '{}' ({:?}) is invalid: {}",
+                           graph.modes[mode], metric, err);
+                    valid = false;
+                }
+            }
+        }
+    }
+
+    if !valid {
+        std::process::exit(1);
+    }
+    info!(target: "graph::validator", "Finished validating graph.");
+}
+
 /// Exposes a graph to a public HTTP endpoint.
 fn run_server(settings_map: HashMap<String, String>) -> () {
     info!(target: "server::builder", "Running server");
@@ -120,14 +205,20 @@ fn run_server(settings_map: HashMap<String, String>) -> () {
     info!(target: "graph::reader", "Reading from {:?}.", graph_file);
     let serializable_graph = SerializableGraph::read_from_file(graph_file);
     info!(target: "graph::reader", "Finished reading. Building rtree now.");
-    let graph = serializable_graph.to_graph();
+    let mut graph = serializable_graph.to_graph();
+    info!(target: "graph::reader", "Linking {} charging stations.", graph.charging_stations.len());
+    graph.link_charging_stations();
     info!(target: "graph::reader", "Finished importing graph.");
 
     // Setting up the router for the web server.
     let mut router = Router::new();
     router.get("/route", endpoints::route_lat_lon, "route");
     router.get("/route-using-ids", endpoints::route_ids, "routeIds");
+    router.get("/pareto-route", endpoints::pareto_route, "paretoRoute");
+    router.get("/energy-route", endpoints::energy_route, "energyRoute");
+    router.get("/charging-route", endpoints::charging_route, "chargingRoute");
     router.get("/reachability", endpoints::reachability, "reachability");
+    router.get("/nearest", endpoints::nearest, "nearest");
 
     let mut mount = Mount::new();
     mount.mount("/api", router);
@@ -0,0 +1,68 @@
+use spade::SpatialObject;
+use spade::BoundingRect;
+use cgmath::Point2;
+
+/// A spatial line segment, representing an edge of the graph (or a segment of its geometry),
+/// to be stored in an R tree from the spade crate.
+#[derive(Debug)]
+pub struct SpatialEdge {
+    /// The coordinates where the segment starts.
+    pub from: Point2<f64>,
+    /// The coordinates where the segment ends.
+    pub to: Point2<f64>,
+    /// The index of the associated edge.
+    pub index: usize,
+    /// The fraction of the edge (from its source) at which the segment starts.
+    pub start: f64,
+    /// The fraction of the edge (from its source) at which the segment ends.
+    pub end: f64,
+}
+
+impl SpatialEdge {
+    /// Create a new segment, covering the given fractions of its edge.
+    pub fn new(from: Point2<f64>, to: Point2<f64>, index: usize, start: f64, end: f64) -> SpatialEdge {
+        SpatialEdge {
+            from: from,
+            to: to,
+            index: index,
+            start: start,
+            end: end,
+        }
+    }
+
+    /// Projects a point onto this segment. Returns the fraction of the edge (from its source)
+    /// at which the projected point lies, as well as the projected point itself.
+    pub fn project(&self, point: &Point2<f64>) -> (f64, Point2<f64>) {
+        let dx = self.to.x - self.from.x;
+        let dy = self.to.y - self.from.y;
+        let length2 = dx * dx + dy * dy;
+        let fraction = if length2 > 0.0 {
+            (((point.x - self.from.x) * dx + (point.y - self.from.y) * dy) / length2).max(0.0).min(1.0)
+        } else {
+            0.0
+        };
+
+        (self.start + fraction * (self.end - self.start),
+         Point2::new(self.from.x + fraction * dx, self.from.y + fraction * dy))
+    }
+}
+
+impl SpatialObject for SpatialEdge {
+    type Point = Point2<f64>;
+
+    fn mbr(&self) -> BoundingRect<Point2<f64>> {
+        BoundingRect::from_corners(&self.from, &self.to)
+    }
+
+    fn distance2(&self, point: &Point2<f64>) -> f64 {
+        let (_, projected) = self.project(point);
+        let dx = projected.x - point.x;
+        let dy = projected.y - point.y;
+        dx * dx + dy * dy
+    }
+
+    // Nothing is contained within a segment.
+    fn contains(&self, _point: &Point2<f64>) -> bool {
+        false
+    }
+}
@@ -8,16 +8,16 @@ use cgmath::Point2;
 pub struct SpatialPoint {
     /// The point's coordinates.
     pub center: Point2<f64>,
-    /// The associated OSM id.
-    pub id: i64,
+    /// The internal id of the associated node.
+    pub index: usize,
 }
 
 impl SpatialPoint {
     /// Create a new point.
-    pub fn new(center: Point2<f64>, id: i64) -> SpatialPoint {
+    pub fn new(center: Point2<f64>, index: usize) -> SpatialPoint {
         SpatialPoint {
             center: center,
-            id: id,
+            index: index,
         }
     }
 }